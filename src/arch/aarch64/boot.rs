@@ -7,6 +7,9 @@ _start:
     /* 1. Mask all interrupts immediately */
     msr daifset, #0xf
 
+    /* Preserve the DTB pointer passed by the loader in x0 */
+    mov x20, x0
+
     /* 2. Check current Exception Level */
     mrs x0, CurrentEL
     lsr x0, x0, #2
@@ -54,7 +57,8 @@ setup_stack:
     ldr x0, =_stack_top
     mov sp, x0
 
-    /* 6. Jump to Rust kmain */
+    /* 6. Jump to Rust kmain(dtb) */
+    mov x0, x20
     bl kmain
 
 hang:
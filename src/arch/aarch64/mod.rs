@@ -1,5 +1,7 @@
 pub mod boot;
 pub mod vectors;
+pub mod rng;
+pub mod psci;
 use core::arch::asm;
 
 /// Returns the current system time in Milliseconds since boot.
@@ -17,4 +19,23 @@ pub fn get_current_time_ms() -> u64 {
     // We use u128 for the intermediate product to prevent overflow 
     // if the kernel stays up for a long time.
     ((cntpct as u128 * 1000) / cntfrq as u128) as u64
-}
\ No newline at end of file
+}
+
+/// Masks IRQs on this CPU and returns the previous DAIF value for `irq_restore`.
+#[inline(always)]
+pub fn irq_save() -> u64 {
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif);
+        asm!("msr daifset, #2");
+    }
+    daif
+}
+
+/// Restores the interrupt mask saved by `irq_save`.
+#[inline(always)]
+pub fn irq_restore(daif: u64) {
+    unsafe {
+        asm!("msr daif, {}", in(reg) daif);
+    }
+}
@@ -0,0 +1,62 @@
+use core::arch::asm;
+
+// PSCI 0.2+ function IDs (SMC32 calling convention)
+const PSCI_SYSTEM_OFF:   u32 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// PSCI calls go through SMC unless the DTB `/psci` node says `hvc`.
+fn use_hvc() -> bool {
+    crate::dtb::get()
+        .and_then(|fdt| fdt.find_node("/psci"))
+        .and_then(|node| node.property_str("method"))
+        == Some("hvc")
+}
+
+/// SMCCC call with no arguments. x1-x3 carry results and SMCCC 1.0
+/// firmware may also corrupt x4-x17, so all of them are clobbered.
+fn call(function: u32) -> u64 {
+    let mut x0 = function as u64;
+    unsafe {
+        if use_hvc() {
+            asm!(
+                "hvc #0",
+                inout("x0") x0,
+                out("x1") _, out("x2") _, out("x3") _, out("x4") _, out("x5") _,
+                out("x6") _, out("x7") _, out("x8") _, out("x9") _, out("x10") _,
+                out("x11") _, out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+                options(nostack),
+            );
+        } else {
+            asm!(
+                "smc #0",
+                inout("x0") x0,
+                out("x1") _, out("x2") _, out("x3") _, out("x4") _, out("x5") _,
+                out("x6") _, out("x7") _, out("x8") _, out("x9") _, out("x10") _,
+                out("x11") _, out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+                options(nostack),
+            );
+        }
+    }
+    x0
+}
+
+/// Powers the machine off. Only returns control if firmware refuses,
+/// in which case the CPU is parked.
+pub fn system_off() -> ! {
+    call(PSCI_SYSTEM_OFF);
+    park()
+}
+
+/// Resets the machine (warm reset on QEMU: RAM, and the log ring, survive).
+pub fn system_reset() -> ! {
+    call(PSCI_SYSTEM_RESET);
+    park()
+}
+
+fn park() -> ! {
+    loop {
+        unsafe { asm!("wfe"); }
+    }
+}
@@ -0,0 +1,235 @@
+use core::arch::asm;
+
+/// Where the DRBG key material came from.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum EntropySource {
+    /// FEAT_RNG `RNDR` instruction.
+    Rndr,
+    /// Timing jitter of the generic counter (no hardware RNG).
+    CounterJitter,
+    /// Counter jitter, reseeded from a virtio entropy device.
+    VirtioRng,
+}
+
+impl EntropySource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EntropySource::Rndr => "RNDR",
+            EntropySource::CounterJitter => "counter jitter",
+            EntropySource::VirtioRng => "virtio-rng",
+        }
+    }
+}
+
+/// ChaCha20-based DRBG with fast key erasure: every request is followed
+/// by a rekey from the same keystream, so a later state leak does not
+/// reveal earlier output.
+struct Drbg {
+    key: [u32; 8],
+    counter: u64,
+}
+
+static mut DRBG: Drbg = Drbg { key: [0; 8], counter: 0 };
+static mut SOURCE: EntropySource = EntropySource::CounterJitter;
+
+/// Detects FEAT_RNG and seeds the DRBG. Must run before `fill_bytes`.
+pub fn init() {
+    let mut seed = [0u32; 8];
+
+    let source = if has_rndr() && seed_from_rndr(&mut seed) {
+        EntropySource::Rndr
+    } else {
+        seed_from_jitter(&mut seed);
+        EntropySource::CounterJitter
+    };
+
+    unsafe {
+        DRBG.key = seed;
+        DRBG.counter = 0;
+        SOURCE = source;
+    }
+}
+
+pub fn source() -> EntropySource {
+    unsafe { SOURCE }
+}
+
+/// Fills `buf` with DRBG output. With RNDR available the key is also
+/// re-mixed with fresh hardware entropy on every call.
+pub fn fill_bytes(buf: &mut [u8]) {
+    unsafe {
+        let drbg = &mut *core::ptr::addr_of_mut!(DRBG);
+
+        if SOURCE == EntropySource::Rndr {
+            for word in drbg.key.iter_mut() {
+                if let Some(r) = rndr() {
+                    *word ^= r as u32;
+                }
+            }
+        }
+
+        let mut block = [0u32; 16];
+        for chunk in buf.chunks_mut(64) {
+            chacha20_block(&drbg.key, drbg.counter, &mut block);
+            drbg.counter = drbg.counter.wrapping_add(1);
+
+            for (i, b) in chunk.iter_mut().enumerate() {
+                *b = (block[i / 4] >> ((i % 4) * 8)) as u8;
+            }
+        }
+
+        // Fast key erasure: the next key is keystream nobody has seen.
+        chacha20_block(&drbg.key, drbg.counter, &mut block);
+        drbg.counter = drbg.counter.wrapping_add(1);
+        drbg.key.copy_from_slice(&block[..8]);
+    }
+}
+
+/// Mixes `seed` into the DRBG key and rekeys, so the next output depends
+/// on both the old state and the new entropy.
+pub fn reseed(seed: &[u8], from: EntropySource) {
+    unsafe {
+        let drbg = &mut *core::ptr::addr_of_mut!(DRBG);
+
+        for (i, b) in seed.iter().enumerate() {
+            drbg.key[(i / 4) % 8] ^= (*b as u32) << ((i % 4) * 8);
+        }
+
+        let mut block = [0u32; 16];
+        chacha20_block(&drbg.key, drbg.counter, &mut block);
+        drbg.counter = drbg.counter.wrapping_add(1);
+        drbg.key.copy_from_slice(&block[..8]);
+
+        // RNDR keeps re-mixing on every call; anything else is an upgrade
+        // over jitter alone.
+        if SOURCE == EntropySource::CounterJitter {
+            SOURCE = from;
+        }
+    }
+}
+
+// ---------------- ENTROPY SOURCES ----------------
+
+/// ID_AA64ISAR0_EL1.RNDR (bits [63:60]) is non-zero when FEAT_RNG is implemented.
+fn has_rndr() -> bool {
+    let isar0: u64;
+    unsafe {
+        asm!("mrs {}, ID_AA64ISAR0_EL1", out(reg) isar0);
+    }
+    (isar0 >> 60) & 0xF != 0
+}
+
+/// Reads RNDR. The instruction sets PSTATE.Z when no random number
+/// could be returned in a reasonable time.
+fn rndr() -> Option<u64> {
+    let val: u64;
+    let ok: u64;
+    unsafe {
+        // RNDR is S3_3_C2_C4_0; spelled out so the assembler does not
+        // need FEAT_RNG enabled for target-cpu=cortex-a72.
+        asm!(
+            "mrs {val}, s3_3_c2_c4_0",
+            "cset {ok}, ne",
+            val = out(reg) val,
+            ok = out(reg) ok,
+            options(nomem, nostack),
+        );
+    }
+    if ok != 0 { Some(val) } else { None }
+}
+
+fn seed_from_rndr(seed: &mut [u32; 8]) -> bool {
+    for pair in seed.chunks_mut(2) {
+        // Retry a few times; a persistent failure falls back to jitter.
+        let mut value = None;
+        for _ in 0..16 {
+            value = rndr();
+            if value.is_some() {
+                break;
+            }
+        }
+
+        let Some(v) = value else { return false };
+        pair[0] = v as u32;
+        pair[1] = (v >> 32) as u32;
+    }
+    true
+}
+
+/// Collects timing jitter from the generic counter around a small
+/// memory-touching workload, folding each delta into the seed. The
+/// counter is slow relative to the CPU, so many samples are taken per
+/// output word.
+fn seed_from_jitter(seed: &mut [u32; 8]) {
+    let mut scratch = [0u64; 64];
+    let mut acc: u64 = read_cntpct();
+
+    for round in 0..(seed.len() * 64) {
+        let start = read_cntpct();
+
+        let n = (acc as usize & 0x3F) + 1;
+        for i in 0..n {
+            let slot = (i * 7 + round) & 0x3F;
+            scratch[slot] = scratch[slot].wrapping_mul(6364136223846793005) ^ acc;
+            acc = acc.rotate_left(5) ^ scratch[slot];
+        }
+
+        let delta = read_cntpct().wrapping_sub(start);
+        acc = acc.rotate_left(7) ^ delta;
+
+        let word = &mut seed[round % seed.len()];
+        *word = word.rotate_left(3) ^ (acc as u32) ^ ((acc >> 32) as u32);
+    }
+
+    // Whiten the raw samples through one ChaCha20 block.
+    let mut block = [0u32; 16];
+    chacha20_block(seed, acc, &mut block);
+    seed.copy_from_slice(&block[..8]);
+}
+
+fn read_cntpct() -> u64 {
+    let cnt: u64;
+    unsafe {
+        asm!("isb", "mrs {}, cntpct_el0", out(reg) cnt);
+    }
+    cnt
+}
+
+// ---------------- CHACHA20 ----------------
+
+#[inline(always)]
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(7);
+}
+
+/// One ChaCha20 block (RFC 8439 layout, 64-bit counter, zero nonce).
+fn chacha20_block(key: &[u32; 8], counter: u64, out: &mut [u32; 16]) {
+    let mut state = [0u32; 16];
+    // "expand 32-byte k"
+    state[0] = 0x6170_7865;
+    state[1] = 0x3320_646e;
+    state[2] = 0x7962_2d32;
+    state[3] = 0x6b20_6574;
+    state[4..12].copy_from_slice(key);
+    state[12] = counter as u32;
+    state[13] = (counter >> 32) as u32;
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    for i in 0..16 {
+        out[i] = working[i].wrapping_add(state[i]);
+    }
+}
@@ -123,10 +123,12 @@ pub extern "C" fn rust_sync_handler() {
         asm!("mrs {}, esr_el1", out(reg) esr);
         asm!("mrs {}, far_el1", out(reg) far);
     }
-    crate::drivers::uart::puts("\n--- PANIC: SYNC EXCEPTION ---\n");
-    crate::drivers::uart::puts("ESR_EL1: "); crate::drivers::uart::putc_hex64(esr);
-    crate::drivers::uart::puts("\nFAR_EL1: "); crate::drivers::uart::putc_hex64(far);
-    crate::drivers::uart::puts("\n----------------------------\n");
+    crate::drivers::uart::enter_polling_mode();
+    crate::log::mark_panic();
+    println!("\n--- PANIC: SYNC EXCEPTION ---");
+    println!("ESR_EL1: {:#018x}", esr);
+    println!("FAR_EL1: {:#018x}", far);
+    println!("----------------------------");
     loop {}
 }
 
@@ -135,17 +137,19 @@ pub extern "C" fn rust_irq_handler() {
     let irq = crate::drivers::gic::acknowledge_irq();
 
     if irq == 30 {
-        crate::drivers::uart::puts("\n[HEARTBEAT] Tick!\n");
+        trace!("Heartbeat tick");
 
         unsafe {
             let freq: u64;
             core::arch::asm!("mrs {}, cntfrq_el0", out(reg) freq);
             core::arch::asm!("msr cntv_tval_el0, {}", in(reg) freq);
         }
+    } else if irq == crate::drivers::uart::UART_IRQ {
+        crate::drivers::uart::handle_irq();
+    } else if crate::drivers::virtio::handle_irq(irq) {
+        // Handled by the owning virtio driver
     } else if irq < 1023 {
-        crate::drivers::uart::puts("\n[IRQ] External interrupt: ");
-        crate::drivers::uart::putc_hex64(irq as u64);
-        crate::drivers::uart::puts("\n");
+        debug!("External interrupt: {}", irq);
     }
 
     crate::drivers::gic::end_of_interrupt(irq);
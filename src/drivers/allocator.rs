@@ -64,4 +64,9 @@ pub fn allocate_aligned(size: usize, align: usize) -> *mut u8 {
 /// USE WITH CAUTION: This invalidates all existing pointers.
 pub fn reset() {
     NEXT.store(0, Ordering::SeqCst);
-}
\ No newline at end of file
+}
+
+/// Bytes handed out so far (including alignment padding) and the arena size.
+pub fn stats() -> (usize, usize) {
+    (NEXT.load(Ordering::SeqCst), ARENA_SIZE)
+}
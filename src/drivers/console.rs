@@ -0,0 +1,164 @@
+use crate::drivers::uart;
+
+/// Longest line the editor accepts; further input is ignored until
+/// the line is submitted or erased.
+pub const LINE_MAX: usize = 128;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+const BS: u8 = 0x08;
+const DEL: u8 = 0x7F;
+const ESC: u8 = 0x1B;
+
+/// What a byte fed to the editor produced.
+pub enum LineEvent {
+    /// Nothing complete yet.
+    Pending,
+    /// Enter was pressed; the line is available through `line()`.
+    Line,
+    /// Ctrl-C discarded the current line.
+    Cancelled,
+    /// Up arrow (ESC [ A), for history.
+    Up,
+    /// Down arrow (ESC [ B), for history.
+    Down,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum EscState {
+    None,
+    Esc,
+    Csi,
+}
+
+/// Minimal line editor for a serial console: echoes printable input,
+/// handles backspace, Ctrl-U (erase line) and Ctrl-C, reports the up and
+/// down arrows and swallows any other ANSI escape sequence so it does not
+/// end up in the line.
+pub struct LineEditor {
+    buf: [u8; LINE_MAX],
+    len: usize,
+    esc: EscState,
+    last_was_cr: bool,
+    submitted: bool,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; LINE_MAX],
+            len: 0,
+            esc: EscState::None,
+            last_was_cr: false,
+            submitted: false,
+        }
+    }
+
+    /// The line submitted by the last `LineEvent::Line`. Valid until the
+    /// next byte is fed.
+    pub fn line(&self) -> &str {
+        // Only printable ASCII is ever stored.
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    pub fn feed(&mut self, b: u8) -> LineEvent {
+        // A submitted line is cleared lazily so `line()` stays valid
+        // until the caller feeds more input.
+        if self.submitted {
+            self.submitted = false;
+            self.len = 0;
+        }
+
+        match self.esc {
+            EscState::Esc => {
+                self.esc = if b == b'[' { EscState::Csi } else { EscState::None };
+                return LineEvent::Pending;
+            }
+            EscState::Csi => {
+                // Parameters and intermediates are 0x20..=0x3F, the final byte ends it.
+                if (0x20..=0x3F).contains(&b) {
+                    return LineEvent::Pending;
+                }
+                self.esc = EscState::None;
+                return match b {
+                    b'A' => LineEvent::Up,
+                    b'B' => LineEvent::Down,
+                    _ => LineEvent::Pending,
+                };
+            }
+            EscState::None => {}
+        }
+
+        // Treat CR LF (and LF after CR) as a single newline.
+        let after_cr = self.last_was_cr;
+        self.last_was_cr = b == b'\r';
+
+        match b {
+            b'\r' | b'\n' => {
+                if b == b'\n' && after_cr {
+                    return LineEvent::Pending;
+                }
+                uart::puts("\n");
+                self.submitted = true;
+                LineEvent::Line
+            }
+            BS | DEL => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    uart::puts("\x08 \x08");
+                }
+                LineEvent::Pending
+            }
+            CTRL_U => {
+                self.erase();
+                LineEvent::Pending
+            }
+            CTRL_C => {
+                self.len = 0;
+                uart::puts("^C\n");
+                LineEvent::Cancelled
+            }
+            ESC => {
+                self.esc = EscState::Esc;
+                LineEvent::Pending
+            }
+            0x20..=0x7E => {
+                if self.len < LINE_MAX {
+                    self.buf[self.len] = b;
+                    self.len += 1;
+                    uart::putc(b);
+                }
+                LineEvent::Pending
+            }
+            _ => LineEvent::Pending,
+        }
+    }
+
+    /// Replaces the line being edited (e.g. with a history entry) and
+    /// redraws it.
+    pub fn set_line(&mut self, line: &str) {
+        if self.submitted {
+            self.submitted = false;
+            self.len = 0;
+        }
+        self.erase();
+
+        for &b in line.as_bytes().iter().take(LINE_MAX) {
+            if (0x20..=0x7E).contains(&b) {
+                self.buf[self.len] = b;
+                self.len += 1;
+            }
+        }
+        // A line is far smaller than the TX ring, so this does not come
+        // up short in practice.
+        uart::write(&self.buf[..self.len]);
+    }
+
+    /// Erases the current line on screen and in the buffer.
+    fn erase(&mut self) {
+        while self.len > 0 {
+            self.len -= 1;
+            uart::puts("\x08 \x08");
+        }
+    }
+}
@@ -1,13 +1,28 @@
 use core::ptr::{read_volatile, write_volatile};
 use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 // QEMU Virt GICv3 MMIO Bases
 const GICD_BASE: usize = 0x08000000; // Distributor
 const GICR_BASE: usize = 0x080A0000; // Redistributor (CPU 0)
 
 // MMIO Offsets
-const GICD_CTLR:   usize = 0x0000;
-const GICR_WAKER:  usize = 0x0014;
+const GICD_CTLR:       usize = 0x0000;
+const GICD_IGROUPR:    usize = 0x0080; // 1 bit per INTID
+const GICD_ISENABLER:  usize = 0x0100; // 1 bit per INTID
+const GICD_IPRIORITYR: usize = 0x0400; // 1 byte per INTID
+const GICD_ICFGR:      usize = 0x0C00; // 2 bits per INTID
+const GICD_IROUTER:    usize = 0x6000; // 8 bytes per INTID (SPIs only)
+const GICR_WAKER:      usize = 0x0014;
+
+/// Default priority for device interrupts (lower value = higher priority).
+const SPI_PRIORITY: u8 = 0xA0;
+
+/// INTIDs 0..1020 are SGIs, PPIs and SPIs; 1020+ are special.
+pub const MAX_INTID: usize = 1020;
+
+/// Number of times each INTID was acknowledged.
+static IRQ_COUNTS: [AtomicU32; MAX_INTID] = [const { AtomicU32::new(0) }; MAX_INTID];
 
 pub fn init() {
     unsafe {
@@ -48,7 +63,18 @@ pub fn acknowledge_irq() -> u32 {
         // Read Interrupt Acknowledge Register for Group 1
         asm!("mrs {}, ICC_IAR1_EL1", out(reg) irq);
     }
-    (irq & 0xFFFFFF) as u32
+    let intid = (irq & 0xFFFFFF) as u32;
+    if (intid as usize) < MAX_INTID {
+        IRQ_COUNTS[intid as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    intid
+}
+
+/// How many times `intid` has been taken since boot.
+pub fn irq_count(intid: u32) -> u32 {
+    IRQ_COUNTS
+        .get(intid as usize)
+        .map_or(0, |c| c.load(Ordering::Relaxed))
 }
 
 pub fn end_of_interrupt(irq: u32) {
@@ -57,4 +83,25 @@ pub fn end_of_interrupt(irq: u32) {
         asm!("msr ICC_EOIR1_EL1, {}", in(reg) irq as u64);
         asm!("isb");
     }
-}
\ No newline at end of file
+}
+
+/// Enables a level-triggered SPI as a Group 1 interrupt routed to CPU 0.
+pub fn enable_spi(intid: u32) {
+    let n = intid as usize;
+    unsafe {
+        // Group 1 (otherwise it would be signalled as FIQ)
+        let igroupr = (GICD_BASE + GICD_IGROUPR + (n / 32) * 4) as *mut u32;
+        write_volatile(igroupr, read_volatile(igroupr) | (1 << (n % 32)));
+
+        // Level-sensitive
+        let icfgr = (GICD_BASE + GICD_ICFGR + (n / 16) * 4) as *mut u32;
+        write_volatile(icfgr, read_volatile(icfgr) & !(0b11 << ((n % 16) * 2)));
+
+        write_volatile((GICD_BASE + GICD_IPRIORITYR + n) as *mut u8, SPI_PRIORITY);
+
+        // Affinity 0.0.0.0, Interrupt_Routing_Mode = 0 (targeted)
+        write_volatile((GICD_BASE + GICD_IROUTER + n * 8) as *mut u64, 0);
+
+        write_volatile((GICD_BASE + GICD_ISENABLER + (n / 32) * 4) as *mut u32, 1 << (n % 32));
+    }
+}
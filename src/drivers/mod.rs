@@ -1,8 +1,13 @@
 pub mod uart;
+pub mod console;
 pub mod virtio_net;
 pub mod gic;
 pub mod allocator;
+pub mod virtio;
 pub mod virtio_queue;
+pub mod virtio_packed;
 pub mod virtio_mmio;
 pub mod virtio_gpu;
-pub mod virtio_pci;
\ No newline at end of file
+pub mod virtio_pci;
+pub mod virtio_blk;
+pub mod virtio_rng;
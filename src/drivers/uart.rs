@@ -1,7 +1,15 @@
+use core::cell::UnsafeCell;
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::arch::aarch64::{irq_restore, irq_save};
+use crate::drivers::gic;
 
 const UART_BASE: usize = 0x09000000;
 
+/// PL011 interrupt on QEMU virt: SPI 1 (DTB `interrupts = <0 1 4>`).
+pub const UART_IRQ: u32 = 33;
+
 // PL011 Register Offsets
 const DR:   usize = 0x00; // Data Register
 const FR:   usize = 0x18; // Flag Register
@@ -9,7 +17,81 @@ const IBRD: usize = 0x24; // Integer Baud Rate
 const FBRD: usize = 0x28; // Fractional Baud Rate
 const LCRH: usize = 0x2C; // Line Control Register
 const CR:   usize = 0x30; // Control Register
+const IFLS: usize = 0x34; // Interrupt FIFO Level Select
 const IMSC: usize = 0x38; // Interrupt Mask Set/Clear
+const MIS:  usize = 0x40; // Masked Interrupt Status
+const ICR:  usize = 0x44; // Interrupt Clear
+
+// FR bits
+const FR_RXFE: u32 = 1 << 4; // RX FIFO empty
+const FR_TXFF: u32 = 1 << 5; // TX FIFO full
+
+// IMSC / MIS / ICR bits
+const INT_RX: u32 = 1 << 4; // RX FIFO level reached
+const INT_TX: u32 = 1 << 5; // TX FIFO level reached
+const INT_RT: u32 = 1 << 6; // RX timeout (data sitting below the level)
+
+// DAIF.I: IRQs masked at the CPU
+const DAIF_I: u64 = 1 << 7;
+
+const RING_SIZE: usize = 1024;
+
+/// Single-producer / single-consumer byte ring. Indices run freely and
+/// wrap; `RING_SIZE` is a power of two so masking picks the slot.
+struct Ring {
+    buf: UnsafeCell<[u8; RING_SIZE]>,
+    head: AtomicUsize, // next slot to write
+    tail: AtomicUsize, // next slot to read
+}
+
+// SAFETY: each side only touches its own index and the slots it owns
+// between them; callers with more than one producer mask IRQs first.
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; RING_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, b: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == RING_SIZE {
+            return false;
+        }
+        unsafe { (*self.buf.get())[head % RING_SIZE] = b; }
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let b = unsafe { (*self.buf.get())[tail % RING_SIZE] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(b)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+static RX_RING: Ring = Ring::new();
+static TX_RING: Ring = Ring::new();
+
+/// Set once the GIC delivers UART interrupts; cleared again for panics.
+static IRQ_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Bytes dropped because the RX ring was full.
+static RX_OVERRUNS: AtomicUsize = AtomicUsize::new(0);
 
 #[inline(always)]
 fn reg(offset: usize) -> *mut u32 {
@@ -30,7 +112,7 @@ pub fn init() {
         // 3. Line Control: 8 bits, no parity, 1 stop bit, FIFO enabled (bit 4)
         write_volatile(reg(LCRH), (0b11 << 5) | (1 << 4));
 
-        // 4. Mask all interrupts for now
+        // 4. Mask all interrupts until the GIC is ready (see enable_interrupts)
         write_volatile(reg(IMSC), 0);
 
         // 5. Enable UART, Transmit, and Receive (bits 0, 8, 9)
@@ -38,30 +120,195 @@ pub fn init() {
     }
 }
 
-pub fn putc(c: u8) {
+/// Switches to interrupt-driven operation. Requires the GIC to be initialized.
+pub fn enable_interrupts() {
     unsafe {
-        // Wait until TX FIFO is not full (FR bit 5)
-        while read_volatile(reg(FR)) & (1 << 5) != 0 {}
-        write_volatile(reg(DR), c as u32);
+        // RXIFLSEL (bits 5:3) = 1/2 full, TXIFLSEL (bits 2:0) = 0 (1/8 full)
+        write_volatile(reg(IFLS), 0b010 << 3);
+        write_volatile(reg(ICR), 0x7FF);
+        write_volatile(reg(IMSC), INT_RX | INT_RT);
+    }
+
+    gic::enable_spi(UART_IRQ);
+    IRQ_MODE.store(true, Ordering::SeqCst);
+}
+
+/// Falls back to busy-wait I/O with UART interrupts masked. Used from
+/// panic and fatal exception paths, where IRQs may never run again:
+/// anything still queued in the TX ring is drained first so the last
+/// log lines are not lost.
+pub fn enter_polling_mode() {
+    IRQ_MODE.store(false, Ordering::SeqCst);
+    unsafe {
+        write_volatile(reg(IMSC), 0);
     }
+    flush();
 }
 
+/// Busy-waits until every byte queued in the TX ring is in the FIFO.
+pub fn flush() {
+    let daif = irq_save();
+    while let Some(b) = TX_RING.pop() {
+        putc_polled(b);
+    }
+    irq_restore(daif);
+}
+
+pub fn putc(c: u8) {
+    // The IRQ handler may print too, so mask IRQs while producing.
+    let daif = irq_save();
+    put_byte(c, daif);
+    irq_restore(daif);
+}
+
+/// Writes a string, translating `\n` to `\r\n`. The whole string is
+/// queued with IRQs masked, so lines from IRQ handlers never land in the
+/// middle of it.
 pub fn puts(s: &str) {
+    let daif = irq_save();
     for b in s.bytes() {
         if b == b'\n' {
-            putc(b'\r');
+            put_byte(b'\r', daif);
         }
-        putc(b);
+        put_byte(b, daif);
     }
+    irq_restore(daif);
 }
 
-pub fn putc_hex64(val: u64) {
-    for i in (0..16).rev() {
-        let nibble = ((val >> (i * 4)) & 0xF) as u8;
-        let c = match nibble {
-            0..=9 => b'0' + nibble,
-            _ => b'A' + (nibble - 10),
-        };
-        putc(c);
+/// Queues one byte. Called with IRQs masked; `caller_daif` is the mask
+/// the caller had before, which decides whether the TX interrupt can
+/// drain the ring later.
+fn put_byte(c: u8, caller_daif: u64) {
+    if !IRQ_MODE.load(Ordering::Relaxed) {
+        putc_polled(c);
+        return;
     }
-}
\ No newline at end of file
+
+    if caller_daif & DAIF_I != 0 {
+        // The caller runs with IRQs masked (early boot, IRQ handlers), so
+        // the TX interrupt cannot drain the ring yet: poll, keeping order.
+        while let Some(b) = TX_RING.pop() {
+            putc_polled(b);
+        }
+        putc_polled(c);
+        return;
+    }
+
+    while !TX_RING.push(c) {
+        // Ring full: make room by feeding the FIFO directly.
+        if let Some(b) = TX_RING.pop() {
+            putc_polled(b);
+        }
+    }
+    start_tx();
+}
+
+/// Queues as much of `buf` as fits without blocking. Returns the number
+/// of bytes accepted.
+pub fn write(buf: &[u8]) -> usize {
+    if !IRQ_MODE.load(Ordering::Relaxed) {
+        let mut n = 0;
+        unsafe {
+            while n < buf.len() && read_volatile(reg(FR)) & FR_TXFF == 0 {
+                write_volatile(reg(DR), buf[n] as u32);
+                n += 1;
+            }
+        }
+        return n;
+    }
+
+    let daif = irq_save();
+    let mut n = 0;
+    while n < buf.len() && TX_RING.push(buf[n]) {
+        n += 1;
+    }
+    start_tx();
+    irq_restore(daif);
+    n
+}
+
+/// Copies received bytes into `buf` without blocking. Returns the number
+/// of bytes read, 0 if nothing is pending.
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    while n < buf.len() {
+        match getc() {
+            Some(b) => {
+                buf[n] = b;
+                n += 1;
+            }
+            None => break,
+        }
+    }
+    n
+}
+
+/// Returns the next received byte, if any.
+pub fn getc() -> Option<u8> {
+    if IRQ_MODE.load(Ordering::Relaxed) {
+        return RX_RING.pop();
+    }
+
+    unsafe {
+        if read_volatile(reg(FR)) & FR_RXFE != 0 {
+            None
+        } else {
+            Some(read_volatile(reg(DR)) as u8)
+        }
+    }
+}
+
+pub fn rx_overruns() -> usize {
+    RX_OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// UART interrupt handler, called from `rust_irq_handler` for `UART_IRQ`.
+pub fn handle_irq() {
+    unsafe {
+        let mis = read_volatile(reg(MIS));
+
+        if mis & (INT_RX | INT_RT) != 0 {
+            while read_volatile(reg(FR)) & FR_RXFE == 0 {
+                let b = read_volatile(reg(DR)) as u8;
+                if !RX_RING.push(b) {
+                    RX_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        if mis & INT_TX != 0 {
+            start_tx();
+        }
+
+        write_volatile(reg(ICR), mis & (INT_RX | INT_RT | INT_TX));
+    }
+}
+
+/// Moves bytes from the TX ring into the FIFO until it fills up. The TX
+/// interrupt is only unmasked while the ring still holds data, otherwise
+/// an idle FIFO would keep it asserted. Called with IRQs masked.
+fn start_tx() {
+    unsafe {
+        while read_volatile(reg(FR)) & FR_TXFF == 0 {
+            match TX_RING.pop() {
+                Some(b) => write_volatile(reg(DR), b as u32),
+                None => break,
+            }
+        }
+
+        let imsc = read_volatile(reg(IMSC));
+        if TX_RING.is_empty() {
+            write_volatile(reg(IMSC), imsc & !INT_TX);
+        } else {
+            write_volatile(reg(IMSC), imsc | INT_TX);
+        }
+    }
+}
+
+fn putc_polled(c: u8) {
+    unsafe {
+        // Wait until TX FIFO is not full (FR bit 5)
+        while read_volatile(reg(FR)) & FR_TXFF != 0 {}
+        write_volatile(reg(DR), c as u32);
+    }
+}
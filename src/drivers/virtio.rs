@@ -0,0 +1,507 @@
+//! Transport-independent virtio pieces: device status bits, the typed
+//! feature set, feature negotiation and the `Transport` trait that lets
+//! drivers run over virtio-mmio or virtio-pci.
+
+use core::fmt;
+use core::ops::{BitAnd, BitOr};
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::arch::aarch64::{irq_restore, irq_save};
+use crate::drivers::gic;
+use crate::drivers::virtio_mmio::{REG_INTERRUPT_ACK, REG_INTERRUPT_STATUS};
+use crate::shell::Command;
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER:      u8 = 2;
+pub const STATUS_DRIVER_OK:   u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED:      u8 = 0x80;
+
+/// 32-bit feature words behind the select registers.
+pub const FEATURE_WORDS: u32 = 4;
+
+// Interrupt status bits
+pub const ISR_QUEUE:  u32 = 1;
+pub const ISR_CONFIG: u32 = 2;
+
+// ---------------- FEATURES ----------------
+
+/// A set of feature bits. The spec leaves room for more than 64, so the
+/// set covers all `FEATURE_WORDS` words.
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub struct Features(u128);
+
+impl Features {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn bit(n: u32) -> Self {
+        Self(1 << n)
+    }
+
+    /// `|` for const contexts.
+    pub const fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Bits in `self` that are not in `other`.
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub fn word(self, index: u32) -> u32 {
+        (self.0 >> (32 * index)) as u32
+    }
+
+    pub fn set_word(&mut self, index: u32, value: u32) {
+        self.0 &= !(0xFFFF_FFFFu128 << (32 * index));
+        self.0 |= (value as u128) << (32 * index);
+    }
+
+    /// Set bit numbers, lowest first.
+    pub fn iter(self) -> impl Iterator<Item = u32> {
+        (0..128).filter(move |&n| self.0 & (1 << n) != 0)
+    }
+
+    /// Prints the set with names from `device_names` for device-specific
+    /// bits and the spec's names for the reserved transport range.
+    pub fn display(self, device_names: &'static [(u32, &'static str)]) -> FeatureNames {
+        FeatureNames { features: self, device_names }
+    }
+}
+
+impl BitOr for Features {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+// Reserved (transport and ring) feature bits, 24..=40
+pub const VIRTIO_RING_F_INDIRECT_DESC: Features = Features::bit(28);
+pub const VIRTIO_RING_F_EVENT_IDX:     Features = Features::bit(29);
+pub const VIRTIO_F_VERSION_1:          Features = Features::bit(32);
+pub const VIRTIO_F_RING_PACKED:        Features = Features::bit(34);
+pub const VIRTIO_F_IN_ORDER:           Features = Features::bit(35);
+
+const TRANSPORT_NAMES: &[(u32, &str)] = &[
+    (28, "INDIRECT_DESC"),
+    (29, "EVENT_IDX"),
+    (32, "VERSION_1"),
+    (33, "ACCESS_PLATFORM"),
+    (34, "RING_PACKED"),
+    (35, "IN_ORDER"),
+    (36, "ORDER_PLATFORM"),
+    (37, "SR_IOV"),
+    (38, "NOTIFICATION_DATA"),
+];
+
+pub struct FeatureNames {
+    features: Features,
+    device_names: &'static [(u32, &'static str)],
+}
+
+impl fmt::Display for FeatureNames {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.features.is_empty() {
+            return f.write_str("none");
+        }
+        for (i, n) in self.features.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            let name = TRANSPORT_NAMES
+                .iter()
+                .chain(self.device_names.iter())
+                .find(|(bit, _)| *bit == n);
+            match name {
+                Some((_, name)) => f.write_str(name)?,
+                None => write!(f, "bit{}", n)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+// ---------------- DEVICE CONFIG ----------------
+
+/// Where a transport keeps the configuration generation counter.
+#[derive(Copy, Clone)]
+pub enum Generation {
+    /// 32-bit register (virtio-mmio `ConfigGeneration`).
+    Word(usize),
+    /// 8-bit field (virtio-pci `config_generation`).
+    Byte(usize),
+}
+
+impl Generation {
+    fn read(self) -> u32 {
+        unsafe {
+            match self {
+                Generation::Word(addr) => read_volatile(addr as *const u32),
+                Generation::Byte(addr) => read_volatile(addr as *const u8) as u32,
+            }
+        }
+    }
+}
+
+/// The device-specific configuration structure. Fields are accessed at
+/// their natural width, as both transports require; accesses past `len`
+/// read as 0 and are not written.
+#[derive(Copy, Clone)]
+pub struct ConfigSpace {
+    base: usize,
+    len: usize,
+    generation: Generation,
+}
+
+impl ConfigSpace {
+    /// # Safety
+    /// `base..base + len` must be the device's mapped config window.
+    pub unsafe fn new(base: usize, len: usize, generation: Generation) -> Self {
+        Self { base, len, generation }
+    }
+
+    /// Runs `f` until it sees a consistent snapshot: the device bumps the
+    /// generation counter whenever config changes, so a read that spans
+    /// a change (or a 64-bit field read in two halves) is retried.
+    pub fn read<R>(&self, f: impl Fn(&Self) -> R) -> R {
+        loop {
+            let before = self.generation.read();
+            let value = f(self);
+            if self.generation.read() == before {
+                return value;
+            }
+        }
+    }
+
+    pub fn u8(&self, offset: usize) -> u8 {
+        self.load::<u8>(offset)
+    }
+
+    pub fn u16(&self, offset: usize) -> u16 {
+        self.load::<u16>(offset)
+    }
+
+    pub fn u32(&self, offset: usize) -> u32 {
+        self.load::<u32>(offset)
+    }
+
+    /// Two 32-bit reads, low half first; only consistent inside `read`.
+    pub fn u64(&self, offset: usize) -> u64 {
+        self.u32(offset) as u64 | (self.u32(offset + 4) as u64) << 32
+    }
+
+    pub fn write_u32(&self, offset: usize, val: u32) {
+        self.store(offset, val);
+    }
+
+    fn load<V: Copy + Default>(&self, offset: usize) -> V {
+        if offset + core::mem::size_of::<V>() > self.len {
+            return V::default();
+        }
+        unsafe { read_volatile((self.base + offset) as *const V) }
+    }
+
+    fn store<V: Copy>(&self, offset: usize, val: V) {
+        if offset + core::mem::size_of::<V>() <= self.len {
+            unsafe { write_volatile((self.base + offset) as *mut V, val) }
+        }
+    }
+}
+
+/// How to read and acknowledge a device's interrupt status. Kept apart
+/// from the transport so the IRQ handler can use it.
+#[derive(Copy, Clone)]
+pub enum Isr {
+    /// virtio-mmio: `InterruptStatus` and `InterruptACK` at `base`.
+    Mmio(usize),
+    /// virtio-pci: the ISR status byte, cleared by reading it.
+    Pci(usize),
+}
+
+impl Isr {
+    /// Returns the pending `ISR_*` bits and acknowledges them, which also
+    /// deasserts a level-triggered line.
+    pub fn ack(self) -> u32 {
+        unsafe {
+            match self {
+                Isr::Mmio(base) => {
+                    let status = read_volatile((base + REG_INTERRUPT_STATUS) as *const u32);
+                    write_volatile(
+                        (base + REG_INTERRUPT_ACK) as *mut u32,
+                        status & (ISR_QUEUE | ISR_CONFIG),
+                    );
+                    status
+                }
+                Isr::Pci(addr) => read_volatile(addr as *const u8) as u32,
+            }
+        }
+    }
+}
+
+// ---------------- NEGOTIATION ----------------
+
+pub enum NegotiationError {
+    /// The device does not offer these required features.
+    Missing(Features),
+    /// The device cleared FEATURES_OK after the driver's selection.
+    Rejected(Features),
+}
+
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NegotiationError::Missing(features) => {
+                write!(f, "device lacks required features: {}", features.display(&[]))
+            }
+            NegotiationError::Rejected(features) => {
+                write!(f, "device rejected features: {}", features.display(&[]))
+            }
+        }
+    }
+}
+
+/// What a driver needs from the bus a virtio device sits on.
+pub trait Transport {
+    fn status(&self) -> u8;
+    fn set_status(&mut self, status: u8);
+
+    /// All feature words the device offers.
+    fn device_features(&mut self) -> Features;
+
+    /// Writes every driver feature word.
+    fn set_driver_features(&mut self, features: Features);
+
+    /// Largest queue the device supports at `index`, 0 if it does not exist.
+    fn max_queue_size(&mut self, index: u16) -> u16;
+
+    /// Programs the ring addresses for queue `index` and enables it. The
+    /// driver and device areas are the avail and used rings of a split
+    /// queue, or the event suppression structures of a packed one.
+    ///
+    /// # Safety
+    /// The rings must be sized for `size` entries and stay allocated for
+    /// as long as the device runs.
+    unsafe fn enable_queue(
+        &mut self,
+        index: u16,
+        size: u16,
+        desc: u64,
+        driver_area: u64,
+        device_area: u64,
+    ) -> bool;
+
+    /// Rings the doorbell for queue `index`.
+    fn notify(&self, index: u16);
+
+    /// The device-specific configuration, if the device has one.
+    fn config(&self) -> Option<ConfigSpace>;
+
+    fn isr(&self) -> Isr;
+
+    /// GIC INTID the device interrupts on, if known.
+    fn irq(&self) -> Option<u32>;
+
+    /// Has `handler` called from the device's interrupt whenever the
+    /// device reports a configuration change. Returns false if the
+    /// transport has no interrupt or config to deliver.
+    fn on_config_change(&self, handler: fn(&ConfigSpace)) -> bool {
+        match (self.irq(), self.config()) {
+            (Some(intid), Some(config)) => register_interrupt(intid, self.isr(), config, handler),
+            _ => false,
+        }
+    }
+
+    /// Resets the device and negotiates features: everything in
+    /// `required` plus whatever part of `optional` the device offers.
+    /// On success the device is at FEATURES_OK and the driver sets up its
+    /// queues before calling `driver_ok`; on failure it is marked FAILED.
+    ///
+    /// # Safety
+    /// Resets the device; any queues set up before are lost.
+    unsafe fn negotiate(
+        &mut self,
+        required: Features,
+        optional: Features,
+    ) -> Result<Features, NegotiationError> {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let offered = self.device_features();
+        debug!("Device features: {}", offered.display(&[]));
+
+        let missing = required.without(offered);
+        if !missing.is_empty() {
+            self.set_status(STATUS_FAILED);
+            return Err(NegotiationError::Missing(missing));
+        }
+
+        let features = required | (optional & offered);
+        self.set_driver_features(features);
+
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.set_status(STATUS_FAILED);
+            return Err(NegotiationError::Rejected(features));
+        }
+
+        Ok(features)
+    }
+
+    /// Completes initialization once the queues are set up.
+    fn driver_ok(&mut self) {
+        let status = self.status();
+        self.set_status(status | STATUS_DRIVER_OK);
+        debug!("Final status: {:#04x}", self.status());
+    }
+}
+
+// ---------------- INTERRUPTS ----------------
+
+const MAX_INTERRUPTS: usize = 8;
+
+#[derive(Copy, Clone)]
+struct InterruptEntry {
+    intid: u32,
+    isr: Isr,
+    config: ConfigSpace,
+    on_config_change: fn(&ConfigSpace),
+}
+
+static mut INTERRUPTS: [Option<InterruptEntry>; MAX_INTERRUPTS] = [None; MAX_INTERRUPTS];
+
+/// Adds a device to the interrupt table and enables its SPI. PCIe INTx
+/// lines are shared, so several devices may register the same INTID.
+pub fn register_interrupt(
+    intid: u32,
+    isr: Isr,
+    config: ConfigSpace,
+    on_config_change: fn(&ConfigSpace),
+) -> bool {
+    let entry = InterruptEntry { intid, isr, config, on_config_change };
+
+    let daif = irq_save();
+    let added = unsafe {
+        let table = &mut *core::ptr::addr_of_mut!(INTERRUPTS);
+        match table.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(entry);
+                true
+            }
+            None => false,
+        }
+    };
+    irq_restore(daif);
+
+    if !added {
+        warn!("virtio interrupt table full, INTID {} not handled", intid);
+        return false;
+    }
+
+    gic::enable_spi(intid);
+    debug!("virtio: config changes on INTID {}", intid);
+    true
+}
+
+/// Called from the IRQ handler. Acknowledges every device registered on
+/// `intid` and runs the config change handlers of those reporting one.
+/// Returns false if no virtio device uses `intid`.
+pub fn handle_irq(intid: u32) -> bool {
+    let table: &[Option<InterruptEntry>; MAX_INTERRUPTS] =
+        unsafe { &*core::ptr::addr_of!(INTERRUPTS) };
+
+    let mut claimed = false;
+    for entry in table.iter().flatten().filter(|e| e.intid == intid) {
+        claimed = true;
+        let status = entry.isr.ack();
+        if status & ISR_CONFIG != 0 {
+            (entry.on_config_change)(&entry.config);
+        }
+        if status & ISR_QUEUE != 0 {
+            trace!("Used ring updated");
+        }
+    }
+    claimed
+}
+
+// ---------------- DEVICE LIST ----------------
+
+const MAX_DEVICES: usize = 8;
+
+/// A device a driver brought up, kept so the shell can show what was
+/// negotiated with it.
+#[derive(Copy, Clone)]
+pub struct DeviceInfo {
+    pub name: &'static str,
+    pub features: Features,
+    /// Names of the device-specific feature bits.
+    pub feature_names: &'static [(u32, &'static str)],
+}
+
+static mut DEVICES: [Option<DeviceInfo>; MAX_DEVICES] = [None; MAX_DEVICES];
+
+/// Records a device's negotiated features and logs them.
+pub fn register_device(info: DeviceInfo) {
+    info!("{}: features {}", info.name, info.features.display(info.feature_names));
+
+    unsafe {
+        let devices = &mut *core::ptr::addr_of_mut!(DEVICES);
+        match devices.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(info),
+            None => warn!("virtio device list full, {} not recorded", info.name),
+        }
+    }
+}
+
+pub fn devices() -> impl Iterator<Item = &'static DeviceInfo> {
+    let devices: &'static [Option<DeviceInfo>; MAX_DEVICES] =
+        unsafe { &*core::ptr::addr_of!(DEVICES) };
+    devices.iter().flatten()
+}
+
+// ---------------- SHELL ----------------
+
+pub static SHELL_COMMANDS: &[Command] = &[
+    Command { name: "virtio", help: "virtio               virtio devices and negotiated features", run: list },
+];
+
+fn list(_args: &[&str]) {
+    let mut any = false;
+    for dev in devices() {
+        println!("{:<12} {:?}", dev.name, dev.features);
+        println!("             {}", dev.features.display(dev.feature_names));
+        any = true;
+    }
+    if !any {
+        println!("no virtio devices");
+    }
+}
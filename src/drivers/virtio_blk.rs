@@ -0,0 +1,101 @@
+use crate::drivers::virtio::{
+    register_device,
+    DeviceInfo,
+    Features,
+    Transport,
+    VIRTIO_F_VERSION_1,
+};
+use crate::drivers::virtio_pci::{self, VirtioPciTransport, VIRTIO_PCI_VENDOR};
+use crate::pci::core::{PciDevice, PciDriver, PciMatch};
+
+//
+// =======================
+//  VIRTIO BLK CONSTANTS
+// =======================
+//
+
+pub const DEVICE_ID_BLK: u16 = 2;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+pub const VIRTIO_BLK_F_RO:       Features = Features::bit(5);
+pub const VIRTIO_BLK_F_BLK_SIZE: Features = Features::bit(6);
+
+// virtio_blk_config layout
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_BLK_SIZE: usize = 20;
+
+const REQUIRED_FEATURES: Features = VIRTIO_F_VERSION_1;
+
+const OPTIONAL_FEATURES: Features = VIRTIO_BLK_F_RO.with(VIRTIO_BLK_F_BLK_SIZE);
+
+const FEATURE_NAMES: &[(u32, &str)] = &[
+    (1, "SIZE_MAX"),
+    (2, "SEG_MAX"),
+    (4, "GEOMETRY"),
+    (5, "RO"),
+    (6, "BLK_SIZE"),
+    (9, "FLUSH"),
+    (10, "TOPOLOGY"),
+    (11, "CONFIG_WCE"),
+    (13, "DISCARD"),
+    (14, "WRITE_ZEROES"),
+];
+
+//
+// =======================
+//  PCI DRIVER
+// =======================
+//
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    ids: &[
+        PciMatch::id(VIRTIO_PCI_VENDOR, virtio_pci::transitional_id(DEVICE_ID_BLK)),
+        PciMatch::id(VIRTIO_PCI_VENDOR, virtio_pci::modern_id(DEVICE_ID_BLK)),
+    ],
+    probe: probe_pci,
+};
+
+/// Negotiates features and reports the disk. No request queue is set up
+/// yet: nothing in the kernel does block I/O.
+fn probe_pci(dev: &PciDevice) -> bool {
+    // SAFETY: the enumerator assigned and enabled the BARs.
+    let Some(mut transport) = (unsafe { VirtioPciTransport::probe(dev) }) else {
+        return false;
+    };
+
+    let features = match unsafe { transport.negotiate(REQUIRED_FEATURES, OPTIONAL_FEATURES) } {
+        Ok(features) => features,
+        Err(e) => {
+            error!("virtio-blk: {}", e);
+            return false;
+        }
+    };
+    let Some(config) = transport.config() else {
+        error!("virtio-blk: no device configuration");
+        return false;
+    };
+
+    transport.driver_ok();
+    register_device(DeviceInfo {
+        name: "virtio-blk",
+        features,
+        feature_names: FEATURE_NAMES,
+    });
+
+    let (capacity, blk_size) = config.read(|c| {
+        (c.u64(CONFIG_CAPACITY), c.u32(CONFIG_BLK_SIZE))
+    });
+
+    info!(
+        "virtio-blk: {} sectors ({} MiB){}",
+        capacity,
+        (capacity * SECTOR_SIZE) >> 20,
+        if features.contains(VIRTIO_BLK_F_RO) { ", read-only" } else { "" },
+    );
+    if features.contains(VIRTIO_BLK_F_BLK_SIZE) {
+        debug!("virtio-blk: block size {}", blk_size);
+    }
+    true
+}
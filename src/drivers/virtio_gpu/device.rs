@@ -1,7 +1,19 @@
 use crate::drivers::allocator;
-use crate::drivers::uart;
-use core::ptr::read_volatile;
-use crate::drivers::virtio_pci::{VirtioPciTransport, VirtQueuePci};
+use crate::drivers::virtio::{
+    register_device,
+    ConfigSpace,
+    DeviceInfo,
+    Features,
+    Transport,
+    VIRTIO_F_IN_ORDER,
+    VIRTIO_F_RING_PACKED,
+    VIRTIO_F_VERSION_1,
+    VIRTIO_RING_F_EVENT_IDX,
+    VIRTIO_RING_F_INDIRECT_DESC,
+};
+use crate::drivers::virtio_pci::{self, VirtioPciTransport, VIRTIO_PCI_VENDOR};
+use crate::drivers::virtio_queue::VirtQueue;
+use crate::pci::core::{PciDevice, PciDriver, PciMatch};
 
 use super::commands::*;
 
@@ -9,21 +21,123 @@ const WIDTH: u32 = 1024;
 const HEIGHT: u32 = 768;
 const RESOURCE_ID: u32 = 1;
 
-pub struct VirtioGpu {
-    transport: VirtioPciTransport,
-    queue: VirtQueuePci,
+const CONTROLQ: u16 = 0;
+const CONTROLQ_SIZE: u16 = 128;
+
+// virtio_gpu_config layout
+const CONFIG_EVENTS_READ:  usize = 0;
+const CONFIG_EVENTS_CLEAR: usize = 4;
+const CONFIG_NUM_SCANOUTS: usize = 8;
+
+const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1;
+
+const REQUIRED_FEATURES: Features = VIRTIO_F_VERSION_1;
+
+/// Every command is a request/response pair, so indirect descriptors
+/// halve the ring usage. The packed layout is used when the device
+/// offers it.
+const OPTIONAL_FEATURES: Features = VIRTIO_RING_F_INDIRECT_DESC
+    .with(VIRTIO_RING_F_EVENT_IDX)
+    .with(VIRTIO_F_RING_PACKED)
+    .with(VIRTIO_F_IN_ORDER);
+
+const FEATURE_NAMES: &[(u32, &str)] = &[
+    (0, "VIRGL"),
+    (1, "EDID"),
+    (2, "RESOURCE_UUID"),
+    (3, "RESOURCE_BLOB"),
+    (4, "CONTEXT_INIT"),
+];
+
+const DEVICE_ID_GPU: u16 = 16;
+
+/// virtio-gpu has no transitional variant.
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "virtio-gpu",
+    ids: &[PciMatch::id(VIRTIO_PCI_VENDOR, virtio_pci::modern_id(DEVICE_ID_GPU))],
+    probe: probe_pci,
+};
+
+/// The scanned-out GPU; owns the framebuffer and control queue.
+static mut PCI_DEVICE: Option<VirtioGpu<VirtioPciTransport>> = None;
+
+fn probe_pci(dev: &PciDevice) -> bool {
+    let slot = unsafe { &mut *core::ptr::addr_of_mut!(PCI_DEVICE) };
+    if slot.is_some() {
+        warn!("virtio-gpu: only one PCI device is supported");
+        return false;
+    }
+
+    // SAFETY: the enumerator assigned and enabled the BARs.
+    let Some(transport) = (unsafe { VirtioPciTransport::probe(dev) }) else {
+        return false;
+    };
+
+    match unsafe { VirtioGpu::new(transport) } {
+        Some(mut gpu) => {
+            info!("GPU driver initialized");
+
+            unsafe { gpu.init_display() };
+
+            info!("Display initialization complete");
+            *slot = Some(gpu);
+            true
+        }
+        None => {
+            error!("GPU driver failed to initialize");
+            false
+        }
+    }
+}
+
+/// Config change handler: the host resized or (dis)connected a display.
+/// The event is acknowledged; the framebuffer keeps its fixed mode.
+fn display_changed(config: &ConfigSpace) {
+    let events = config.read(|c| c.u32(CONFIG_EVENTS_READ));
+    if events & VIRTIO_GPU_EVENT_DISPLAY != 0 {
+        info!("virtio-gpu: display configuration changed");
+    }
+    config.write_u32(CONFIG_EVENTS_CLEAR, events);
+}
+
+pub struct VirtioGpu<T: Transport> {
+    transport: T,
+    queue: VirtQueue,
     framebuffer: *mut u8,
 }
 
-impl VirtioGpu {
+impl<T: Transport> VirtioGpu<T> {
+
+    pub unsafe fn new(mut transport: T) -> Option<Self> {
+
+        let features = match transport.negotiate(REQUIRED_FEATURES, OPTIONAL_FEATURES) {
+            Ok(features) => features,
+            Err(e) => {
+                error!("virtio-gpu: {}", e);
+                return None;
+            }
+        };
+
+        let mut queue = VirtQueue::new(&mut transport, CONTROLQ, CONTROLQ_SIZE, features)?;
 
-    pub unsafe fn new(mut transport: VirtioPciTransport) -> Option<Self> {
+        // Commands are completed by polling.
+        queue.disable_interrupts();
 
-        if !transport.handshake() {
-            return None;
+        transport.driver_ok();
+        register_device(DeviceInfo {
+            name: "virtio-gpu",
+            features,
+            feature_names: FEATURE_NAMES,
+        });
+
+        if let Some(config) = transport.config() {
+            let scanouts = config.read(|c| c.u32(CONFIG_NUM_SCANOUTS));
+            info!("virtio-gpu: {} scanout(s)", scanouts);
         }
 
-        let queue = transport.setup_queue(0, 128)?;
+        if !transport.on_config_change(display_changed) {
+            debug!("No interrupt for display events");
+        }
 
         let fb_size = (WIDTH * HEIGHT * 4) as usize;
         let framebuffer =
@@ -47,64 +161,47 @@ impl VirtioGpu {
         })
     }
 
-    unsafe fn submit_cmd<T>(&mut self, cmd: &T) {
-        // 1. Allocate Descriptors from our new allocator
-        let idx_cmd = self.queue.alloc_desc().expect("GPU Out of Descriptors (Cmd)");
-        let idx_resp = self.queue.alloc_desc().expect("GPU Out of Descriptors (Resp)");
-
-        // 2. Setup Command Descriptor (Read-only for device)
-        let d_cmd = self.queue.desc.add(idx_cmd as usize);
-        (*d_cmd).addr = cmd as *const T as u64;
-        (*d_cmd).len = core::mem::size_of::<T>() as u32;
-        (*d_cmd).flags = 1; // VIRTQ_DESC_F_NEXT
-        (*d_cmd).next = idx_resp;
-
-        // 3. Setup Response Descriptor (Write-only for device)
+    unsafe fn submit_cmd<C>(&mut self, cmd: &C) {
         // We use a static RESP to avoid stack allocation issues in no_std
         static mut RESP: GpuResp = GpuResp {
             hdr: GpuCtrlHdr { type_: 0, flags: 0, fence_id: 0, ctx_id: 0, padding: 0 }
         };
-        
-        // FIX: Use addr_of_mut! to get a raw pointer to the packed field
-        let resp_type_ptr = core::ptr::addr_of_mut!(RESP.hdr.type_);
-        core::ptr::write_volatile(resp_type_ptr, 0);
-
-        let d_resp = self.queue.desc.add(idx_resp as usize);
-        (*d_resp).addr = core::ptr::addr_of!(RESP) as u64;
-        (*d_resp).len = core::mem::size_of::<GpuResp>() as u32;
-        (*d_resp).flags = 2; // VIRTQ_DESC_F_WRITE
-        (*d_resp).next = 0;
-
-        // 4. Submit to the Transport
-        self.transport.submit(&mut self.queue, idx_cmd);
-
-        // 5. Polling with Volatile Read (Crucial for AArch64)
-        // FIX: Use addr_of! for the used ring index as well
-        let used_idx_ptr = core::ptr::addr_of!((*self.queue.used).idx);
-        while core::ptr::read_volatile(used_idx_ptr) == self.queue.last_used_idx {
-            core::hint::spin_loop();
+        core::ptr::write_volatile(core::ptr::addr_of_mut!(RESP.hdr.type_), 0);
+
+        let cmd_bytes = core::slice::from_raw_parts(
+            cmd as *const C as *const u8,
+            core::mem::size_of::<C>(),
+        );
+        let resp_bytes = core::slice::from_raw_parts_mut(
+            core::ptr::addr_of_mut!(RESP) as *mut u8,
+            core::mem::size_of::<GpuResp>(),
+        );
+
+        // Command is read by the device, the response written by it.
+        let token = self.queue
+            .add(&[cmd_bytes], &mut [resp_bytes])
+            .expect("GPU control queue full");
+        self.queue.kick(&self.transport);
+
+        // `cmd` lives in the caller's frame, so wait for completion.
+        loop {
+            match self.queue.pop_used() {
+                Some((done, _)) if done == token => break,
+                Some((done, _)) => warn!("Unexpected completion {} (waiting for {})", done, token),
+                None => core::hint::spin_loop(),
+            }
         }
 
-        // 6. Check Response
-        // FIX: Use addr_of! to read the response type
         let resp_type = core::ptr::read_volatile(core::ptr::addr_of!(RESP.hdr.type_));
-        
-        // 7. Housekeeping
-        self.queue.last_used_idx = self.queue.last_used_idx.wrapping_add(1);
-        
-        // IMPORTANT: Return descriptors to the free list for next command!
-        self.queue.free_desc_chain(idx_cmd);
 
         // Check for success: 0x1100 (OK) or 0x1101 (OK_NODATA)
         if resp_type != 0x1100 && resp_type != 0x1101 {
-            uart::puts("[GPU] Command Failed! Error: ");
-            uart::putc_hex64(resp_type as u64);
-            uart::puts("\n");
+            error!("Command failed, response: {:#06x}", resp_type);
         }
     }
 
     pub unsafe fn init_display(&mut self) {
-        uart::puts("[GPU] Initializing display...\n");
+        info!("Initializing display...");
 
         // ---------------------------------------------------
         // 1. GET_DISPLAY_INFO
@@ -138,9 +235,7 @@ impl VirtioGpu {
         };
         self.submit_cmd(&create);
 
-        uart::puts("[GPU] FB addr: ");
-        uart::putc_hex64(self.framebuffer as u64);
-        uart::puts("\n");
+        debug!("FB addr: {:#x}", self.framebuffer as u64);
 
         // ---------------------------------------------------
         // 3. RESOURCE_ATTACH_BACKING
@@ -239,6 +334,6 @@ impl VirtioGpu {
         };
         self.submit_cmd(&flush);
 
-        uart::puts("[GPU] Initialization complete. Check QEMU window!\n");
+        info!("Initialization complete. Check QEMU window!");
     }
 }
\ No newline at end of file
@@ -1,7 +1,8 @@
 use core::ptr::{read_volatile, write_volatile};
-use crate::drivers::uart;
-use crate::drivers::virtio_queue::{VirtQueue, VirtqDesc, VirtqAvail, VirtqUsed};
-use core::sync::atomic::{self, Ordering};
+
+use crate::dtb;
+
+use crate::drivers::virtio::{ConfigSpace, Features, Generation, Isr, Transport, FEATURE_WORDS};
 
 //
 // =======================
@@ -14,7 +15,9 @@ pub const REG_VERSION:         usize = 0x004;
 pub const REG_DEVICE_ID:       usize = 0x008;
 pub const REG_VENDOR_ID:       usize = 0x00c;
 pub const REG_DEVICE_FEATURES: usize = 0x010;
+pub const REG_DEVICE_FEATURES_SEL: usize = 0x014;
 pub const REG_DRIVER_FEATURES: usize = 0x020;
+pub const REG_DRIVER_FEATURES_SEL: usize = 0x024;
 pub const REG_QUEUE_SEL:       usize = 0x030;
 pub const REG_QUEUE_NUM_MAX:   usize = 0x034;
 pub const REG_QUEUE_NUM:       usize = 0x038;
@@ -23,6 +26,8 @@ pub const REG_QUEUE_NOTIFY:    usize = 0x050;
 pub const REG_INTERRUPT_STATUS:usize = 0x060;
 pub const REG_INTERRUPT_ACK:   usize = 0x064;
 pub const REG_STATUS:          usize = 0x070;
+pub const REG_CONFIG_GENERATION: usize = 0x0fc;
+pub const REG_CONFIG:          usize = 0x100;
 
 pub const REG_QUEUE_DESC_LOW:   usize = 0x080;
 pub const REG_QUEUE_DESC_HIGH:  usize = 0x084;
@@ -31,167 +36,166 @@ pub const REG_QUEUE_AVAIL_HIGH: usize = 0x094;
 pub const REG_QUEUE_USED_LOW:   usize = 0x0a0;
 pub const REG_QUEUE_USED_HIGH:  usize = 0x0a4;
 
-//
-// =======================
-//  STATUS FLAGS
-// =======================
-//
+const MAGIC: u32 = 0x74726976; // "virt"
 
-pub const STATUS_ACKNOWLEDGE: u32 = 1;
-pub const STATUS_DRIVER:      u32 = 2;
-pub const STATUS_DRIVER_OK:   u32 = 4;
-pub const STATUS_FEATURES_OK: u32 = 8;
+/// Device config size; QEMU spaces the slots 0x200 apart.
+const CONFIG_LEN: usize = 0x100;
+
+/// First SPI of the virtio-mmio slots; the DTB gives each slot's offset
+/// from it in `interrupts = <0 n 1>`.
+const SPI_BASE: u32 = 32;
+
+/// Only the virtio 1.x register layout is supported; legacy (version 1)
+/// devices use a page-frame queue interface.
+const MODERN_VERSION: u32 = 2;
 
 //
 // =======================
-//  GENERIC PROBE
+//  TRANSPORT
 // =======================
 //
 
-pub unsafe fn probe(base: usize, expected_device_id: u32) -> bool {
-    let magic = read_volatile(base as *const u32);
-    if magic != 0x74726976 { // "virt"
-        return false;
+pub struct MmioTransport {
+    base: usize,
+    irq: Option<u32>,
+}
+
+impl MmioTransport {
+    /// Checks for a virtio-mmio v2 device at `base`. Empty QEMU slots have
+    /// device ID 0 and are skipped.
+    ///
+    /// # Safety
+    /// `base` must be a mapped virtio-mmio register window.
+    pub unsafe fn new(base: usize) -> Option<Self> {
+        let t = Self { base, irq: None };
+
+        if t.read(REG_MAGIC) != MAGIC {
+            return None;
+        }
+
+        let version = t.read(REG_VERSION);
+        if version != MODERN_VERSION {
+            warn!("virtio-mmio at {:#x}: unsupported version {}", base, version);
+            return None;
+        }
+
+        if t.device_id() == 0 {
+            return None;
+        }
+
+        Some(t)
     }
 
-    let device_id = read_volatile((base + REG_DEVICE_ID) as *const u32);
-    device_id == expected_device_id
-}
+    pub fn device_id(&self) -> u32 {
+        self.read(REG_DEVICE_ID)
+    }
 
-//
-// =======================
-//  GENERIC HANDSHAKE
-// =======================
-//
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
 
-pub unsafe fn init_device(base: usize) {
-    uart::puts("[VirtIO] Initializing device...\n");
+    fn write(&self, reg: usize, val: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, val) }
+    }
+}
 
-    // 1. Reset
-    write_volatile((base + REG_STATUS) as *mut u32, 0);
+/// Finds the first virtio-mmio slot in the DTB holding a device of type
+/// `device_id`. QEMU virt lists 32 slots whether or not they are used.
+pub fn find_device(device_id: u32) -> Option<MmioTransport> {
+    let root = dtb::get()?.root()?;
+
+    root.children()
+        .filter(|node| node.property_str("compatible") == Some("virtio,mmio"))
+        .filter_map(|node| {
+            // reg = <addr_hi addr_lo size_hi size_lo> (#address-cells = 2)
+            let reg = node.property("reg")?;
+            let addr = u64::from_be_bytes(reg.get(..8)?.try_into().ok()?);
+            // SAFETY: the DTB describes this window as virtio-mmio.
+            let mut t = unsafe { MmioTransport::new(addr as usize) }?;
+
+            // interrupts = <type number flags>, type 0 being an SPI
+            t.irq = node.property("interrupts").and_then(|irq| {
+                Some(SPI_BASE + u32::from_be_bytes(irq.get(4..8)?.try_into().ok()?))
+            });
+            Some(t)
+        })
+        .find(|t| t.device_id() == device_id)
+}
 
-    // 2. ACK + DRIVER
-    let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
-    write_volatile((base + REG_STATUS) as *mut u32, status);
+impl Transport for MmioTransport {
+    fn status(&self) -> u8 {
+        self.read(REG_STATUS) as u8
+    }
 
-    // 3. Feature negotiation (accept all for now)
-    let features = read_volatile((base + REG_DEVICE_FEATURES) as *const u32);
-    write_volatile((base + REG_DRIVER_FEATURES) as *mut u32, features);
+    fn set_status(&mut self, status: u8) {
+        self.write(REG_STATUS, status as u32);
+    }
 
-    // 4. FEATURES_OK
-    status |= STATUS_FEATURES_OK;
-    write_volatile((base + REG_STATUS) as *mut u32, status);
+    fn device_features(&mut self) -> Features {
+        let mut features = Features::empty();
+        for word in 0..FEATURE_WORDS {
+            self.write(REG_DEVICE_FEATURES_SEL, word);
+            features.set_word(word, self.read(REG_DEVICE_FEATURES));
+        }
+        features
+    }
 
-    // 5. Verify FEATURES_OK
-    let check = read_volatile((base + REG_STATUS) as *const u32);
-    if (check & STATUS_FEATURES_OK) == 0 {
-        uart::puts("[FATAL] VirtIO device rejected features.\n");
-        loop {}
+    fn set_driver_features(&mut self, features: Features) {
+        for word in 0..FEATURE_WORDS {
+            self.write(REG_DRIVER_FEATURES_SEL, word);
+            self.write(REG_DRIVER_FEATURES, features.word(word));
+        }
     }
 
-    // 6. DRIVER_OK
-    status |= STATUS_DRIVER_OK;
-    write_volatile((base + REG_STATUS) as *mut u32, status);
+    fn max_queue_size(&mut self, index: u16) -> u16 {
+        self.write(REG_QUEUE_SEL, index as u32);
+        self.read(REG_QUEUE_NUM_MAX).min(u16::MAX as u32) as u16
+    }
 
-    let final_status = read_volatile((base + REG_STATUS) as *const u32);
-    uart::puts("[VirtIO] Device Ready. Status: ");
-    uart::putc_hex64(final_status as u64);
-    uart::puts("\n");
-}
+    unsafe fn enable_queue(
+        &mut self,
+        index: u16,
+        size: u16,
+        desc: u64,
+        driver_area: u64,
+        device_area: u64,
+    ) -> bool {
+        self.write(REG_QUEUE_SEL, index as u32);
+        self.write(REG_QUEUE_NUM, size as u32);
 
-//
-// =======================
-//  GENERIC QUEUE SETUP
-// =======================
-//
+        self.write(REG_QUEUE_DESC_LOW, desc as u32);
+        self.write(REG_QUEUE_DESC_HIGH, (desc >> 32) as u32);
+
+        self.write(REG_QUEUE_AVAIL_LOW, driver_area as u32);
+        self.write(REG_QUEUE_AVAIL_HIGH, (driver_area >> 32) as u32);
+
+        self.write(REG_QUEUE_USED_LOW, device_area as u32);
+        self.write(REG_QUEUE_USED_HIGH, (device_area >> 32) as u32);
 
-pub unsafe fn setup_queue(base: usize, q_idx: u16) -> VirtQueue {
-    write_volatile((base + REG_QUEUE_SEL) as *mut u32, q_idx as u32);
-
-    let q_max = read_volatile((base + REG_QUEUE_NUM_MAX) as *const u32);
-    let q_size = if q_max < 128 { q_max } else { 128 };
-
-    write_volatile((base + REG_QUEUE_NUM) as *mut u32, q_size);
-
-    // ----------------------------
-    // Descriptor Table
-    // ----------------------------
-    let desc_size = q_size as usize * core::mem::size_of::<VirtqDesc>();
-
-    let desc = crate::drivers::allocator::allocate_aligned(
-        desc_size,
-        16,
-    ) as *mut VirtqDesc;
-
-    core::ptr::write_bytes(desc as *mut u8, 0, desc_size);
-
-    // ----------------------------
-    // Available Ring
-    // Layout:
-    // flags (2)
-    // idx   (2)
-    // ring  (2 * q_size)
-    // used_event (2)
-    // ----------------------------
-    let avail_size = 4 + (q_size as usize * 2) + 2;
-
-    let avail = crate::drivers::allocator::allocate_aligned(
-        avail_size,
-        2,
-    ) as *mut VirtqAvail;
-
-    core::ptr::write_bytes(avail as *mut u8, 0, avail_size);
-
-    // ----------------------------
-    // Used Ring
-    // Layout:
-    // flags (2)
-    // idx   (2)
-    // ring  (8 * q_size)
-    // avail_event (2)
-    // ----------------------------
-    let used_size = 4 + (q_size as usize * 8) + 2;
-
-    let used = crate::drivers::allocator::allocate_aligned(
-        used_size,
-        4,
-    ) as *mut VirtqUsed;
-
-    core::ptr::write_bytes(used as *mut u8, 0, used_size);
-
-    // ----------------------------
-    // Register physical addresses
-    // ----------------------------
-    write_volatile((base + REG_QUEUE_DESC_LOW) as *mut u32, desc as usize as u32);
-    write_volatile((base + REG_QUEUE_DESC_HIGH) as *mut u32, ((desc as usize as u64) >> 32) as u32);
-
-    write_volatile((base + REG_QUEUE_AVAIL_LOW) as *mut u32, avail as usize as u32);
-    write_volatile((base + REG_QUEUE_AVAIL_HIGH) as *mut u32, ((avail as usize as u64) >> 32) as u32);
-
-    write_volatile((base + REG_QUEUE_USED_LOW) as *mut u32, used as usize as u32);
-    write_volatile((base + REG_QUEUE_USED_HIGH) as *mut u32, ((used as usize as u64) >> 32) as u32);
-
-    write_volatile((base + REG_QUEUE_READY) as *mut u32, 1);
-
-    uart::puts("[VirtIO] Queue configured.\n");
-
-    VirtQueue {
-        desc,
-        avail,
-        used,
-        queue_idx: q_idx,
-        last_used_idx: 0,
-        size: q_size as u16,
+        self.write(REG_QUEUE_READY, 1);
+        true
     }
-}
 
-//
-// =======================
-//  QUEUE NOTIFY
-// =======================
-//
+    fn notify(&self, index: u16) {
+        self.write(REG_QUEUE_NOTIFY, index as u32);
+    }
+
+    fn config(&self) -> Option<ConfigSpace> {
+        // SAFETY: the config window follows the registers.
+        Some(unsafe {
+            ConfigSpace::new(
+                self.base + REG_CONFIG,
+                CONFIG_LEN,
+                Generation::Word(self.base + REG_CONFIG_GENERATION),
+            )
+        })
+    }
+
+    fn isr(&self) -> Isr {
+        Isr::Mmio(self.base)
+    }
 
-pub unsafe fn notify_queue(base: usize, queue_index: u16) {
-    atomic::fence(Ordering::SeqCst);
-    write_volatile((base + REG_QUEUE_NOTIFY) as *mut u32, queue_index as u32);
-}
\ No newline at end of file
+    fn irq(&self) -> Option<u32> {
+        self.irq
+    }
+}
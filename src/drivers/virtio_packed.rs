@@ -0,0 +1,350 @@
+//! Packed virtqueue layout (virtio 1.1, VIRTIO_F_RING_PACKED).
+//!
+//! A single descriptor ring is shared with the device. Ownership of each
+//! slot is tracked with the AVAIL/USED flag bits against a wrap counter
+//! that flips every time an index passes the end of the ring, and both
+//! sides publish their notification preferences in small event
+//! suppression structures instead of ring flags.
+
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+
+use crate::drivers::allocator;
+use crate::drivers::virtio_queue::{
+    dmb_ish,
+    dmb_ishst,
+    need_event,
+    Buffer,
+    VirtqDesc,
+    MAX_INDIRECT,
+    VIRTQ_DESC_F_INDIRECT,
+    VIRTQ_DESC_F_NEXT,
+};
+
+// Descriptor ownership bits
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED:  u16 = 1 << 15;
+
+// Event suppression flags
+const RING_EVENT_FLAGS_ENABLE:  u16 = 0;
+const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+/// Only with EVENT_IDX: notify at the descriptor in `off_wrap`.
+const RING_EVENT_FLAGS_DESC:    u16 = 2;
+
+/// `off_wrap` bit 15 carries the wrap counter, bits 14:0 the offset.
+const EVENT_WRAP_SHIFT: u16 = 15;
+
+#[repr(C, align(16))]
+#[derive(Copy, Clone)]
+struct PackedDesc {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+
+#[repr(C, align(4))]
+struct EventSuppress {
+    off_wrap: u16,
+    flags: u16,
+}
+
+/// Packed ring state. Tokens are buffer IDs, handed out from a free list
+/// separate from the ring slots; `chain_len` remembers how many slots
+/// each in-flight buffer occupies so completions can be skipped over.
+///
+/// With IN_ORDER a buffer's ID is its head slot instead. Buffers then
+/// complete in ring order, and one used descriptor may stand for a whole
+/// batch ending at the buffer it names.
+pub struct PackedRing {
+    size: u16,
+
+    desc: *mut PackedDesc,
+    /// Driver event suppression: read by the device.
+    driver_event: *mut EventSuppress,
+    /// Device event suppression: written by the device.
+    device_event: *mut EventSuppress,
+
+    /// Indirect tables indexed by buffer ID, or null.
+    indirect: *mut PackedDesc,
+    event_idx: bool,
+
+    next_avail: u16,
+    avail_wrap: bool,
+    next_used: u16,
+    used_wrap: bool,
+
+    /// Free ring slots.
+    num_free: u16,
+    /// Slots made available since the last kick.
+    num_added: u16,
+
+    free_id: u16,
+    id_next: *mut u16,
+    chain_len: *mut u16,
+
+    /// Device-writable bytes per in-flight ID; null unless IN_ORDER.
+    written: *mut u32,
+    /// IN_ORDER: ID and length of the used descriptor that completes the
+    /// batch being returned.
+    batch: Option<(u16, u32)>,
+}
+
+impl PackedRing {
+    pub unsafe fn new(
+        size: u16,
+        indirect: *mut VirtqDesc,
+        event_idx: bool,
+        written: *mut u32,
+    ) -> Option<Self> {
+        let desc = allocator::allocate_aligned(size as usize * size_of::<PackedDesc>(), 16)
+            as *mut PackedDesc;
+        let driver_event = allocator::allocate_aligned(size_of::<EventSuppress>(), 4)
+            as *mut EventSuppress;
+        let device_event = allocator::allocate_aligned(size_of::<EventSuppress>(), 4)
+            as *mut EventSuppress;
+        let id_next = allocator::allocate_aligned(size as usize * 2, 2) as *mut u16;
+        let chain_len = allocator::allocate_aligned(size as usize * 2, 2) as *mut u16;
+
+        if desc.is_null() || driver_event.is_null() || device_event.is_null()
+            || id_next.is_null() || chain_len.is_null()
+        {
+            error!("virtqueue: out of DMA memory");
+            return None;
+        }
+
+        for i in 0..size {
+            *id_next.add(i as usize) = i + 1;
+        }
+
+        let mut ring = Self {
+            size,
+            desc,
+            driver_event,
+            device_event,
+            indirect: indirect as *mut PackedDesc,
+            event_idx,
+            next_avail: 0,
+            avail_wrap: true,
+            next_used: 0,
+            used_wrap: true,
+            num_free: size,
+            num_added: 0,
+            free_id: 0,
+            id_next,
+            chain_len,
+            written,
+            batch: None,
+        };
+
+        ring.set_interrupts(true);
+        Some(ring)
+    }
+
+    pub fn areas(&self) -> (u64, u64, u64) {
+        (self.desc as u64, self.driver_event as u64, self.device_event as u64)
+    }
+
+    fn in_order(&self) -> bool {
+        !self.written.is_null()
+    }
+
+    pub unsafe fn add(
+        &mut self,
+        buffers: impl Iterator<Item = Buffer>,
+        count: usize,
+        written: u32,
+    ) -> Option<u16> {
+        let use_indirect = count > 1 && count <= MAX_INDIRECT && !self.indirect.is_null();
+        let slots = if use_indirect { 1 } else { count };
+
+        if slots > self.num_free as usize {
+            return None;
+        }
+
+        let id = if self.in_order() {
+            self.next_avail
+        } else {
+            let id = self.free_id;
+            self.free_id = *self.id_next.add(id as usize);
+            id
+        };
+
+        let head_slot = self.next_avail;
+        let head_wrap = self.avail_wrap;
+
+        let head = if use_indirect {
+            let table = self.indirect.add(id as usize * MAX_INDIRECT);
+            for (i, (addr, len, flags)) in buffers.enumerate() {
+                // Entries of an indirect table are read in order; only
+                // the WRITE flag is meaningful.
+                table.add(i).write_volatile(PackedDesc { addr, len, id: 0, flags });
+            }
+            self.advance_avail();
+
+            PackedDesc {
+                addr: table as u64,
+                len: (count * size_of::<PackedDesc>()) as u32,
+                id,
+                flags: VIRTQ_DESC_F_INDIRECT | ownership(head_wrap),
+            }
+        } else {
+            let mut head = None;
+            for (i, (addr, len, flags)) in buffers.enumerate() {
+                let next = if i + 1 < count { VIRTQ_DESC_F_NEXT } else { 0 };
+                let d = PackedDesc {
+                    addr,
+                    len,
+                    id,
+                    flags: flags | next | ownership(self.avail_wrap),
+                };
+
+                if i == 0 {
+                    head = Some(d);
+                } else {
+                    self.desc.add(self.next_avail as usize).write_volatile(d);
+                }
+                self.advance_avail();
+            }
+            head?
+        };
+
+        // The head is made available last, so the device never sees a
+        // partial chain: its flags go out after everything else.
+        let d = self.desc.add(head_slot as usize);
+        write_volatile(addr_of_mut!((*d).addr), head.addr);
+        write_volatile(addr_of_mut!((*d).len), head.len);
+        write_volatile(addr_of_mut!((*d).id), head.id);
+        dmb_ishst();
+        write_volatile(addr_of_mut!((*d).flags), head.flags);
+
+        *self.chain_len.add(id as usize) = slots as u16;
+        if self.in_order() {
+            *self.written.add(id as usize) = written;
+        }
+        self.num_free -= slots as u16;
+        self.num_added = self.num_added.wrapping_add(slots as u16);
+
+        Some(id)
+    }
+
+    /// Follows the device event suppression structure. With EVENT_IDX
+    /// the event offset is compared against the slots added since the
+    /// last kick, adjusting for a wrap counter mismatch as Linux does.
+    pub fn kick_needed(&mut self) -> bool {
+        let new = self.next_avail;
+        let old = new.wrapping_sub(self.num_added);
+        self.num_added = 0;
+
+        // Read both fields at once so they are consistent.
+        let snapshot = unsafe { read_volatile(self.device_event as *const u32) };
+        let off_wrap = snapshot as u16;
+        let flags = (snapshot >> 16) as u16;
+
+        match flags {
+            RING_EVENT_FLAGS_DISABLE => false,
+            RING_EVENT_FLAGS_DESC if self.event_idx => {
+                let wrap = off_wrap >> EVENT_WRAP_SHIFT != 0;
+                let mut event = off_wrap & !(1 << EVENT_WRAP_SHIFT);
+                if wrap != self.avail_wrap {
+                    event = event.wrapping_sub(self.size);
+                }
+                need_event(event, new, old)
+            }
+            _ => true,
+        }
+    }
+
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        unsafe {
+            let ev = self.driver_event;
+            if !enabled {
+                write_volatile(addr_of_mut!((*ev).flags), RING_EVENT_FLAGS_DISABLE);
+            } else if self.event_idx {
+                write_volatile(addr_of_mut!((*ev).off_wrap), self.used_off_wrap());
+                // Offset before the flags that make the device use it
+                dmb_ishst();
+                write_volatile(addr_of_mut!((*ev).flags), RING_EVENT_FLAGS_DESC);
+            } else {
+                write_volatile(addr_of_mut!((*ev).flags), RING_EVENT_FLAGS_ENABLE);
+            }
+        }
+    }
+
+    /// A slot is used once its AVAIL and USED bits both match the wrap
+    /// counter the driver expects for it.
+    pub fn has_used(&self) -> bool {
+        if self.batch.is_some() {
+            return true;
+        }
+        let flags = unsafe {
+            read_volatile(addr_of!((*self.desc.add(self.next_used as usize)).flags))
+        };
+        let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+        let used = flags & VIRTQ_DESC_F_USED != 0;
+        avail == used && used == self.used_wrap
+    }
+
+    pub unsafe fn pop_used(&mut self, interrupts: bool) -> (u16, u32) {
+        let (id, len) = match self.batch {
+            Some(batch) => batch,
+            None => {
+                let d = self.desc.add(self.next_used as usize);
+                (read_volatile(addr_of!((*d).id)), read_volatile(addr_of!((*d).len)))
+            }
+        };
+
+        // In order, the used descriptor sits at the oldest buffer's head
+        // and completes every buffer up to the one it names.
+        let (id, len) = if !self.in_order() {
+            (id, len)
+        } else if self.next_used == id {
+            self.batch = None;
+            (id, len)
+        } else {
+            self.batch = Some((id, len));
+            (self.next_used, *self.written.add(self.next_used as usize))
+        };
+
+        // The device writes one used descriptor per buffer; skip the
+        // rest of the slots the buffer occupied.
+        let slots = *self.chain_len.add(id as usize);
+        self.num_free += slots;
+        self.next_used += slots;
+        if self.next_used >= self.size {
+            self.next_used -= self.size;
+            self.used_wrap = !self.used_wrap;
+        }
+
+        if !self.in_order() {
+            *self.id_next.add(id as usize) = self.free_id;
+            self.free_id = id;
+        }
+
+        if self.event_idx && interrupts {
+            // Ask for an interrupt when the next buffer is used.
+            write_volatile(addr_of_mut!((*self.driver_event).off_wrap), self.used_off_wrap());
+            dmb_ish();
+        }
+
+        (id, len)
+    }
+
+    fn advance_avail(&mut self) {
+        self.next_avail += 1;
+        if self.next_avail == self.size {
+            self.next_avail = 0;
+            self.avail_wrap = !self.avail_wrap;
+        }
+    }
+
+    fn used_off_wrap(&self) -> u16 {
+        self.next_used | ((self.used_wrap as u16) << EVENT_WRAP_SHIFT)
+    }
+}
+
+/// AVAIL/USED bits that make a descriptor available in the lap whose
+/// wrap counter is `wrap`: AVAIL equal to it, USED the inverse.
+fn ownership(wrap: bool) -> u16 {
+    if wrap { VIRTQ_DESC_F_AVAIL } else { VIRTQ_DESC_F_USED }
+}
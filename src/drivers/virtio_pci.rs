@@ -1,20 +1,30 @@
 use core::ptr::{read_volatile, write_volatile};
 
-use crate::drivers::uart;
-use crate::drivers::allocator;
-use crate::drivers::virtio_queue::{
-    VirtqDesc,
-    VirtqAvail,
-    VirtqUsed,
-};
-
-const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1;
-const VIRTIO_STATUS_DRIVER: u8 = 2;
-const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
-const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
-const VIRTIO_STATUS_FAILED: u8 = 0x80;
-
-const VIRTIO_F_VERSION_1: u32 = 1;
+use crate::drivers::virtio::{ConfigSpace, Features, Generation, Isr, Transport, FEATURE_WORDS};
+use crate::pci::core::PciDevice;
+
+/// Queues per device whose notify offsets are cached.
+const MAX_QUEUES: usize = 16;
+
+pub const VIRTIO_PCI_VENDOR: u16 = 0x1AF4;
+
+/// Transitional devices use 0x1000 + (device type - 1); modern-only
+/// ones 0x1040 + device type.
+pub const fn transitional_id(device_type: u16) -> u16 {
+    0x1000 + device_type - 1
+}
+
+pub const fn modern_id(device_type: u16) -> u16 {
+    0x1040 + device_type
+}
+
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+
+// virtio-pci capability types
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG:    u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
 
 #[repr(C)]
 pub struct VirtioPciCommonCfg {
@@ -42,247 +52,194 @@ pub struct VirtioPciCommonCfg {
     pub queue_used_hi: u32,
 }
 
-pub struct VirtQueuePci {
-    pub desc: *mut VirtqDesc,
-    pub avail: *mut VirtqAvail,
-    pub used: *mut VirtqUsed,
-    pub size: u16,
-    pub last_used_idx: u16,
-    // Allocator State
-    pub free_head: u16,
-    pub num_free: u16,
-}
-
-impl VirtQueuePci {
-    /// Initialize the free list by linking all descriptors together
-    pub unsafe fn init_allocator(&mut self) {
-        for i in 0..(self.size - 1) {
-            (*self.desc.add(i as usize)).next = i + 1;
-        }
-        // Last one points to a sentinel/null
-        (*self.desc.add((self.size - 1) as usize)).next = 0xFFFF; 
-        
-        self.free_head = 0;
-        self.num_free = self.size;
-    }
-
-    /// Pull a descriptor from the free list
-    pub unsafe fn alloc_desc(&mut self) -> Option<u16> {
-        if self.num_free == 0 || self.free_head == 0xFFFF {
-            return None;
-        }
-
-        let id = self.free_head;
-        self.free_head = (*self.desc.add(id as usize)).next;
-        self.num_free -= 1;
-        
-        // Clean the descriptor for fresh use
-        let d = self.desc.add(id as usize);
-        (*d).addr = 0;
-        (*d).len = 0;
-        (*d).flags = 0;
-        (*d).next = 0;
-
-        Some(id)
-    }
-
-    /// Return a descriptor (or a whole chain) to the free list
-    pub unsafe fn free_desc_chain(&mut self, mut head: u16) {
-        while head != 0xFFFF {
-            let d = self.desc.add(head as usize);
-            let next = (*d).next;
-            let flags = (*d).flags;
-
-            // Link this back into our free list
-            (*d).next = self.free_head;
-            self.free_head = head;
-            self.num_free += 1;
-
-            // If VIRTQ_DESC_F_NEXT (1) isn't set, this was the end of the chain
-            if (flags & 1) == 0 {
-                break;
-            }
-            head = next;
-        }
-    }
-}
-
 pub struct VirtioPciTransport {
     pub common_cfg: *mut VirtioPciCommonCfg,
     pub notify_base: usize,
     pub notify_off_multiplier: u32,
+    /// `queue_notify_off` of each enabled queue, read once at setup so
+    /// `notify` does not have to go through `queue_select`.
+    notify_offs: [u16; MAX_QUEUES],
+    isr: usize,
+    device_cfg: Option<(usize, usize)>,
+    irq: Option<u32>,
 }
 
 impl VirtioPciTransport {
 
-    pub unsafe fn new(
-        common_cfg: usize,
-        notify_base: usize,
-        notify_off_multiplier: u32,
-    ) -> Self {
-        Self {
+    /// Locates the virtio structures through the vendor capabilities of
+    /// an enumerated function. Each one points into one of its BARs.
+    ///
+    /// # Safety
+    /// The function's BARs must be assigned and decoding.
+    pub unsafe fn probe(dev: &PciDevice) -> Option<Self> {
+        let mut common_cfg = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_cfg = None;
+
+        for (cap, id) in dev.capabilities() {
+            if id != PCI_CAP_ID_VENDOR {
+                continue;
+            }
+
+            // cap_vndr, cap_next, cap_len, cfg_type | bar, id, padding
+            let cfg_type = (dev.config_read(cap) >> 24) as u8;
+            let bar = dev.config_read(cap + 0x04) as u8;
+            let offset = dev.config_read(cap + 0x08) as u64;
+            let length = dev.config_read(cap + 0x0C) as usize;
+
+            let Some(base) = dev.bars.get(bar as usize).copied().flatten() else {
+                continue;
+            };
+            let addr = (base.cpu_addr + offset) as usize;
+
+            // A device may offer several capabilities of a type, the
+            // preferred one first; the driver uses the first it can map.
+            match cfg_type {
+                VIRTIO_PCI_CAP_COMMON_CFG => {
+                    common_cfg.get_or_insert(addr);
+                }
+                VIRTIO_PCI_CAP_NOTIFY_CFG => {
+                    notify.get_or_insert_with(|| (addr, dev.config_read(cap + 0x10)));
+                }
+                VIRTIO_PCI_CAP_ISR_CFG => {
+                    isr.get_or_insert(addr);
+                }
+                VIRTIO_PCI_CAP_DEVICE_CFG => {
+                    device_cfg.get_or_insert((addr, length));
+                }
+                _ => {}
+            }
+        }
+
+        let (Some(common_cfg), Some((notify_base, notify_off_multiplier)), Some(isr)) =
+            (common_cfg, notify, isr)
+        else {
+            warn!(
+                "{:02x}:{:02x}.{}: virtio-pci capabilities missing",
+                dev.bus, dev.dev, dev.func,
+            );
+            return None;
+        };
+
+        Some(Self {
             common_cfg: common_cfg as *mut _,
             notify_base,
             notify_off_multiplier,
-        }
+            notify_offs: [0; MAX_QUEUES],
+            isr,
+            device_cfg,
+            irq: dev.irq,
+        })
     }
+}
 
-    // ---------------- HANDSHAKE ----------------
-
-    pub unsafe fn handshake(&mut self) -> bool {
-        let cfg = &mut *self.common_cfg;
-
-        uart::puts("[VIRTIO] Starting handshake...\n");
-
-        write_volatile(&mut cfg.device_status, 0);
-
-        write_volatile(&mut cfg.device_status, VIRTIO_STATUS_ACKNOWLEDGE);
-
-        write_volatile(
-            &mut cfg.device_status,
-            VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER,
-        );
-
-        write_volatile(&mut cfg.device_feature_select, 0);
-        let device_features = read_volatile(&cfg.device_feature);
+impl Transport for VirtioPciTransport {
 
-        uart::puts("[VIRTIO] Device features: ");
-        uart::putc_hex64(device_features as u64);
-        uart::puts("\n");
+    // ---------------- STATUS AND FEATURES ----------------
 
-        write_volatile(&mut cfg.driver_feature_select, 0);
-        write_volatile(&mut cfg.driver_feature, VIRTIO_F_VERSION_1);
+    fn status(&self) -> u8 {
+        unsafe { read_volatile(&(*self.common_cfg).device_status) }
+    }
 
-        let mut status = read_volatile(&cfg.device_status);
-        status |= VIRTIO_STATUS_FEATURES_OK;
-        write_volatile(&mut cfg.device_status, status);
+    fn set_status(&mut self, status: u8) {
+        unsafe { write_volatile(&mut (*self.common_cfg).device_status, status) }
+    }
 
-        let verify = read_volatile(&cfg.device_status);
-        if (verify & VIRTIO_STATUS_FEATURES_OK) == 0 {
-            uart::puts("[VIRTIO] FEATURES_OK rejected\n");
-            write_volatile(&mut cfg.device_status, VIRTIO_STATUS_FAILED);
-            return false;
+    fn device_features(&mut self) -> Features {
+        let mut features = Features::empty();
+        unsafe {
+            let cfg = &mut *self.common_cfg;
+            for word in 0..FEATURE_WORDS {
+                write_volatile(&mut cfg.device_feature_select, word);
+                features.set_word(word, read_volatile(&cfg.device_feature));
+            }
         }
+        features
+    }
 
-        status |= VIRTIO_STATUS_DRIVER_OK;
-        write_volatile(&mut cfg.device_status, status);
-
-        let final_status = read_volatile(&cfg.device_status);
-
-        uart::puts("[VIRTIO] Final status: ");
-        uart::putc_hex64(final_status as u64);
-        uart::puts("\n");
-
-        true
+    fn set_driver_features(&mut self, features: Features) {
+        unsafe {
+            let cfg = &mut *self.common_cfg;
+            for word in 0..FEATURE_WORDS {
+                write_volatile(&mut cfg.driver_feature_select, word);
+                write_volatile(&mut cfg.driver_feature, features.word(word));
+            }
+        }
     }
 
     // ---------------- QUEUE SETUP ----------------
 
-    pub unsafe fn setup_queue(
+    fn max_queue_size(&mut self, index: u16) -> u16 {
+        unsafe {
+            let cfg = &mut *self.common_cfg;
+            write_volatile(&mut cfg.queue_select, index);
+            read_volatile(&cfg.queue_size)
+        }
+    }
+
+    unsafe fn enable_queue(
         &mut self,
-        queue_index: u16,
+        index: u16,
         size: u16,
-    ) -> Option<VirtQueuePci> {
-
-        let cfg = &mut *self.common_cfg;
-
-        write_volatile(&mut cfg.queue_select, queue_index);
-
-        let max_size = read_volatile(&cfg.queue_size);
-        if max_size == 0 {
-            return None;
+        desc: u64,
+        driver_area: u64,
+        device_area: u64,
+    ) -> bool {
+        if index as usize >= MAX_QUEUES {
+            error!("Queue {} beyond the {} supported", index, MAX_QUEUES);
+            return false;
         }
 
-        let actual_size = if size > max_size { max_size } else { size };
-        write_volatile(&mut cfg.queue_size, actual_size);
-
-        let desc_size =
-            actual_size as usize * core::mem::size_of::<VirtqDesc>();
-
-        let avail_size =
-            core::mem::size_of::<VirtqAvail>();
-
-        let used_size =
-            core::mem::size_of::<VirtqUsed>();
-
-        let desc =
-            allocator::allocate_aligned(desc_size, 4096) as *mut VirtqDesc;
-
-        let avail =
-            allocator::allocate_aligned(avail_size, 4096) as *mut VirtqAvail;
+        let cfg = &mut *self.common_cfg;
 
-        let used =
-            allocator::allocate_aligned(used_size, 4096) as *mut VirtqUsed;
+        write_volatile(&mut cfg.queue_select, index);
+        write_volatile(&mut cfg.queue_size, size);
 
         // Write split 64-bit addresses correctly
         write_volatile(&mut cfg.queue_desc_lo, desc as u32);
-        write_volatile(&mut cfg.queue_desc_hi, (desc as u64 >> 32) as u32);
-
-        write_volatile(&mut cfg.queue_avail_lo, avail as u32);
-        write_volatile(&mut cfg.queue_avail_hi, (avail as u64 >> 32) as u32);
-
-        write_volatile(&mut cfg.queue_used_lo, used as u32);
-        write_volatile(&mut cfg.queue_used_hi, (used as u64 >> 32) as u32);
+        write_volatile(&mut cfg.queue_desc_hi, (desc >> 32) as u32);
 
-        write_volatile(&mut cfg.queue_enable, 1);
+        write_volatile(&mut cfg.queue_avail_lo, driver_area as u32);
+        write_volatile(&mut cfg.queue_avail_hi, (driver_area >> 32) as u32);
 
-        uart::puts("[VIRTIO] Queue enabled\n");
-
-        // Note: allocator::allocate_aligned now zeros the memory, 
-        // but explicit zeroing here is kept for clarity.
-        core::ptr::write_bytes(desc as *mut u8, 0, desc_size);
-        core::ptr::write_bytes(avail as *mut u8, 0, avail_size);
-        core::ptr::write_bytes(used as *mut u8, 0, used_size);
-
-        let mut vq = VirtQueuePci {
-            desc,
-            avail,
-            used,
-            size: actual_size,
-            last_used_idx: 0,
-            free_head: 0,
-            num_free: 0,
-        };
-        
-        vq.init_allocator();
-        Some(vq)
-    }
+        write_volatile(&mut cfg.queue_used_lo, device_area as u32);
+        write_volatile(&mut cfg.queue_used_hi, (device_area >> 32) as u32);
 
-    // ---------------- SUBMIT ----------------
+        self.notify_offs[index as usize] = read_volatile(&cfg.queue_notify_off);
 
-    pub unsafe fn submit(&self, queue: &mut VirtQueuePci, head_index: u16) {
-        let avail = &mut *queue.avail;
-        let slot = avail.idx % queue.size;
-        
-        avail.ring[slot as usize] = head_index;
-
-        // Ensure descriptor and avail.ring writes are visible before idx update
-        core::arch::asm!("dmb ishst", options(nostack));
-
-        avail.idx = avail.idx.wrapping_add(1);
+        write_volatile(&mut cfg.queue_enable, 1);
 
-        // Ensure idx update is visible before notify
-        core::arch::asm!("dmb ishst", options(nostack));
+        debug!("Queue {} enabled ({} entries)", index, size);
 
-        self.notify(0); // Notify queue 0
+        true
     }
 
     // ---------------- NOTIFY ----------------
 
-    pub unsafe fn notify(&self, queue_index: u16) {
-
-        let cfg = &mut *self.common_cfg;
-
-        let queue_notify_off =
-            read_volatile(&cfg.queue_notify_off);
-
+    fn notify(&self, index: u16) {
         let addr =
             self.notify_base +
-            (queue_notify_off as usize *
+            (self.notify_offs[index as usize] as usize *
             self.notify_off_multiplier as usize);
 
-        let notify_ptr = addr as *mut u16;
+        unsafe { write_volatile(addr as *mut u16, index); }
+    }
+
+    // ---------------- CONFIG AND INTERRUPTS ----------------
 
-        write_volatile(notify_ptr, queue_index);
+    fn config(&self) -> Option<ConfigSpace> {
+        let (base, len) = self.device_cfg?;
+        let generation = unsafe { core::ptr::addr_of!((*self.common_cfg).config_generation) };
+        // SAFETY: the window comes from the device's config capability.
+        Some(unsafe { ConfigSpace::new(base, len, Generation::Byte(generation as usize)) })
     }
-}
\ No newline at end of file
+
+    fn isr(&self) -> Isr {
+        Isr::Pci(self.isr)
+    }
+
+    fn irq(&self) -> Option<u32> {
+        self.irq
+    }
+}
@@ -1,11 +1,35 @@
-pub struct VirtQueue {
-    pub desc: *mut VirtqDesc,
-    pub avail: *mut VirtqAvail,
-    pub used: *mut VirtqUsed,
-    pub queue_idx: u16,
-    pub last_used_idx: u16,
-    pub size: u16, // Added this
-}
+use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+
+use crate::drivers::allocator;
+use crate::drivers::virtio::{
+    Features,
+    Transport,
+    VIRTIO_F_IN_ORDER,
+    VIRTIO_F_RING_PACKED,
+    VIRTIO_RING_F_EVENT_IDX,
+    VIRTIO_RING_F_INDIRECT_DESC,
+};
+use crate::drivers::virtio_packed::PackedRing;
+
+// Descriptor flags
+pub const VIRTQ_DESC_F_NEXT:  u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+// Ring flags, used when EVENT_IDX is not negotiated
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+/// Split rings are limited to 32768 entries by the spec.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+/// Descriptors per indirect table. Longer requests use a direct chain.
+pub const MAX_INDIRECT: usize = 16;
+
+/// End of the descriptor free list.
+const NO_DESC: u16 = 0xFFFF;
 
 #[repr(C, align(16))]
 #[derive(Copy, Clone)]
@@ -16,22 +40,6 @@ pub struct VirtqDesc {
     pub next: u16,
 }
 
-#[repr(C)]
-pub struct VirtqAvail {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [u16; 128],
-    pub used_event: u16,
-}
-
-#[repr(C)]
-pub struct VirtqUsed {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [VirtqUsedElem; 128],
-    pub avail_event: u16,
-}
-
 #[repr(C, align(4))]
 #[derive(Copy, Clone)]
 pub struct VirtqUsedElem {
@@ -39,12 +47,609 @@ pub struct VirtqUsedElem {
     pub len: u32,
 }
 
+/// One buffer of a request as the rings see it: address, length and
+/// VIRTQ_DESC_F_WRITE if the device writes it.
+pub type Buffer = (u64, u32, u16);
+
+/// Which ring layout a queue was negotiated with.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum RingKind {
+    Split,
+    Packed,
+}
+
+impl RingKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RingKind::Split => "split",
+            RingKind::Packed => "packed",
+        }
+    }
+}
+
+/// A virtqueue over either the split or the packed (virtio 1.1) ring
+/// layout, chosen from the negotiated features. Drivers only see tokens:
+/// `add` hands one out per request and `pop_used` returns it once the
+/// device is done.
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    ring: Ring,
+    /// Whether the driver wants used buffer interrupts.
+    interrupts: bool,
+    /// Doorbell writes so far, for measuring notification suppression.
+    notifications: u64,
+}
+
+enum Ring {
+    Split(SplitRing),
+    Packed(PackedRing),
+}
+
 impl VirtQueue {
-    pub unsafe fn add_desc(&mut self, i: u16, addr: u64, len: u32, flags: u16) {
-        let d = self.desc.add(i as usize);
-        (*d).addr = addr;
-        (*d).len = len;
-        (*d).flags = flags;
-        (*d).next = 0;
-    }
-}
\ No newline at end of file
+    /// Allocates a queue of up to `max_size` entries and hands it to the
+    /// device through `transport`. The size is clamped to what the device
+    /// supports and rounded down to a power of two. `features` is the
+    /// negotiated set; the ring features in it pick the layout and
+    /// enable indirect descriptors and event suppression.
+    ///
+    /// # Safety
+    /// Must run during device initialization, before the queue is used.
+    pub unsafe fn new<T: Transport>(
+        transport: &mut T,
+        index: u16,
+        max_size: u16,
+        features: Features,
+    ) -> Option<Self> {
+        let device_max = transport.max_queue_size(index);
+        if device_max == 0 {
+            return None;
+        }
+
+        let wanted = max_size.min(device_max).min(MAX_QUEUE_SIZE);
+        if wanted == 0 {
+            return None;
+        }
+        // Highest power of two not above `wanted`
+        let size = 1u16 << (15 - wanted.leading_zeros());
+
+        // One table of MAX_INDIRECT descriptors per token. Split and
+        // packed descriptors are both 16 bytes.
+        let indirect = if features.contains(VIRTIO_RING_F_INDIRECT_DESC) {
+            let table_size = size as usize * MAX_INDIRECT * size_of::<VirtqDesc>();
+            let table = allocator::allocate_aligned(table_size, 16) as *mut VirtqDesc;
+            if table.is_null() {
+                error!("virtqueue {}: out of DMA memory", index);
+                return None;
+            }
+            table
+        } else {
+            core::ptr::null_mut()
+        };
+
+        let event_idx = features.contains(VIRTIO_RING_F_EVENT_IDX);
+        let in_order = features.contains(VIRTIO_F_IN_ORDER);
+
+        // With IN_ORDER the device may complete a batch with one used
+        // entry, so the lengths of the others come from what was posted.
+        let written = if in_order {
+            let written = allocator::allocate_aligned(size as usize * 4, 4) as *mut u32;
+            if written.is_null() {
+                error!("virtqueue {}: out of DMA memory", index);
+                return None;
+            }
+            written
+        } else {
+            core::ptr::null_mut()
+        };
+
+        let ring = if features.contains(VIRTIO_F_RING_PACKED) {
+            Ring::Packed(PackedRing::new(size, indirect, event_idx, written)?)
+        } else {
+            Ring::Split(SplitRing::new(size, indirect, event_idx, written)?)
+        };
+
+        let vq = Self { index, size, ring, interrupts: true, notifications: 0 };
+
+        let (desc, driver_area, device_area) = vq.areas();
+        if !transport.enable_queue(index, size, desc, driver_area, device_area) {
+            return None;
+        }
+
+        debug!(
+            "Queue {} configured ({} entries, {}{}{}{})",
+            index, size,
+            vq.kind().as_str(),
+            if indirect.is_null() { "" } else { ", indirect" },
+            if event_idx { ", event-idx" } else { "" },
+            if in_order { ", in-order" } else { "" },
+        );
+
+        Some(vq)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn kind(&self) -> RingKind {
+        match self.ring {
+            Ring::Split(_) => RingKind::Split,
+            Ring::Packed(_) => RingKind::Packed,
+        }
+    }
+
+    pub fn notifications(&self) -> u64 {
+        self.notifications
+    }
+
+    /// Exposes a chain of buffers to the device: `inputs` are read by the
+    /// device, `outputs` are written by it. Returns the token that
+    /// `pop_used` reports on completion, or `None` when the chain is empty
+    /// or there are not enough free descriptors.
+    ///
+    /// With INDIRECT_DESC, multi-buffer requests take a single ring
+    /// descriptor pointing at a per-token indirect table (as Linux does),
+    /// so a full table of scatter-gather requests fits in the ring.
+    ///
+    /// The device is not notified; call `kick` once a batch is queued.
+    ///
+    /// # Safety
+    /// Every buffer must stay valid, and must not be accessed by the CPU,
+    /// until its token comes back from `pop_used`.
+    pub unsafe fn add(&mut self, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u16> {
+        let count = inputs.len() + outputs.len();
+        if count == 0 {
+            return None;
+        }
+        let written = outputs.iter().map(|b| b.len() as u32).sum();
+
+        let buffers = inputs
+            .iter()
+            .map(|b| (b.as_ptr() as u64, b.len() as u32, 0))
+            .chain(outputs.iter_mut().map(|b| (b.as_mut_ptr() as u64, b.len() as u32, VIRTQ_DESC_F_WRITE)));
+
+        match &mut self.ring {
+            Ring::Split(r) => r.add(buffers, count, written),
+            Ring::Packed(r) => r.add(buffers, count, written),
+        }
+    }
+
+    /// Tells the device about new buffers, unless its event suppression
+    /// settings say it does not need to hear about them.
+    pub fn kick<T: Transport>(&mut self, transport: &T) {
+        // The ring updates must be visible before the device's
+        // suppression state is read, and before the doorbell write.
+        dmb_ish();
+
+        let needed = match &mut self.ring {
+            Ring::Split(r) => r.kick_needed(),
+            Ring::Packed(r) => r.kick_needed(),
+        };
+
+        if needed {
+            self.notifications += 1;
+            transport.notify(self.index);
+        }
+    }
+
+    /// Stops used buffer interrupts for a polling driver. Best effort: the
+    /// device may still interrupt.
+    pub fn disable_interrupts(&mut self) {
+        self.interrupts = false;
+        match &mut self.ring {
+            Ring::Split(r) => r.disable_interrupts(),
+            Ring::Packed(r) => r.set_interrupts(false),
+        }
+    }
+
+    /// Re-arms used buffer interrupts. Returns false if buffers completed
+    /// in the meantime, in which case the caller should poll again rather
+    /// than wait for an interrupt.
+    pub fn enable_interrupts(&mut self) -> bool {
+        self.interrupts = true;
+        match &mut self.ring {
+            Ring::Split(r) => r.enable_interrupts(),
+            Ring::Packed(r) => r.set_interrupts(true),
+        }
+        // Publish the re-arm before checking for completions it missed.
+        dmb_ish();
+        !self.has_used()
+    }
+
+    /// True when the device has completed buffers not yet popped.
+    pub fn has_used(&self) -> bool {
+        match &self.ring {
+            Ring::Split(r) => r.has_used(),
+            Ring::Packed(r) => r.has_used(),
+        }
+    }
+
+    /// Takes the next completed request off the ring and frees its
+    /// descriptors. Yields the token from `add` and the number of bytes
+    /// the device wrote.
+    ///
+    /// With IN_ORDER a single used entry may complete a whole batch;
+    /// its requests are still returned one per call, oldest first.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+
+        // Order the used check before reading the entry it covers.
+        dmb_ishld();
+
+        let interrupts = self.interrupts;
+        // SAFETY: `has_used` saw a completion the device has published.
+        unsafe {
+            match &mut self.ring {
+                Ring::Split(r) => Some(r.pop_used(interrupts)),
+                Ring::Packed(r) => Some(r.pop_used(interrupts)),
+            }
+        }
+    }
+
+    /// Addresses of the descriptor table, driver area and device area,
+    /// as programmed into the transport.
+    fn areas(&self) -> (u64, u64, u64) {
+        match &self.ring {
+            Ring::Split(r) => (r.desc as u64, r.avail as u64, r.used as u64),
+            Ring::Packed(r) => r.areas(),
+        }
+    }
+}
+
+// ---------------- SPLIT RING ----------------
+
+// The avail and used rings are sized at runtime, so they are addressed
+// through these header structs plus an offset rather than fixed arrays.
+//
+//   avail: flags u16, idx u16, ring[size] u16,           used_event u16
+//   used:  flags u16, idx u16, ring[size] VirtqUsedElem, avail_event u16
+
+#[repr(C)]
+struct RingHeader {
+    flags: u16,
+    idx: u16,
+}
+
+const RING_HEADER_SIZE: usize = size_of::<RingHeader>();
+
+/// Split ring state. Descriptors not owned by the device are kept on a
+/// free list threaded through their `next` fields; a token is the head
+/// descriptor of its chain.
+///
+/// With IN_ORDER the list is the table itself, walked in ring order and
+/// wrapping at the end: chains are never spliced back, so the free
+/// descriptors are the `num_free` after `free_head` and the oldest
+/// in-flight chain starts right behind them.
+struct SplitRing {
+    size: u16,
+
+    desc: *mut VirtqDesc,
+    avail: *mut RingHeader,
+    used: *mut RingHeader,
+
+    free_head: u16,
+    num_free: u16,
+
+    /// Next used ring entry the driver has not consumed yet.
+    last_used_idx: u16,
+
+    /// Indirect tables indexed by head descriptor, or null.
+    indirect: *mut VirtqDesc,
+
+    /// EVENT_IDX negotiated: notifications are driven by `used_event` and
+    /// `avail_event` instead of the ring flags.
+    event_idx: bool,
+    /// `avail.idx` when the device was last notified.
+    kicked_idx: u16,
+
+    /// Device-writable bytes per in-flight head; null unless IN_ORDER.
+    written: *mut u32,
+    /// IN_ORDER: head and length of the used entry that completes the
+    /// batch being returned.
+    batch: Option<(u16, u32)>,
+}
+
+impl SplitRing {
+    unsafe fn new(
+        size: u16,
+        indirect: *mut VirtqDesc,
+        event_idx: bool,
+        written: *mut u32,
+    ) -> Option<Self> {
+        let desc_size = size as usize * size_of::<VirtqDesc>();
+        let avail_size = RING_HEADER_SIZE + size as usize * 2 + 2;
+        let used_size = RING_HEADER_SIZE + size as usize * size_of::<VirtqUsedElem>() + 2;
+
+        // Alignment per the virtio 1.x split ring layout; the allocator
+        // returns zeroed memory.
+        let desc = allocator::allocate_aligned(desc_size, 16) as *mut VirtqDesc;
+        let avail = allocator::allocate_aligned(avail_size, 2) as *mut RingHeader;
+        let used = allocator::allocate_aligned(used_size, 4) as *mut RingHeader;
+
+        if desc.is_null() || avail.is_null() || used.is_null() {
+            error!("virtqueue: out of DMA memory");
+            return None;
+        }
+
+        let ring = Self {
+            size,
+            desc,
+            avail,
+            used,
+            free_head: 0,
+            num_free: size,
+            last_used_idx: 0,
+            indirect,
+            event_idx,
+            kicked_idx: 0,
+            written,
+            batch: None,
+        };
+
+        for i in 0..size {
+            let next = if i + 1 < size {
+                i + 1
+            } else if ring.in_order() {
+                0
+            } else {
+                NO_DESC
+            };
+            write_volatile(addr_of_mut!((*ring.desc_ptr(i)).next), next);
+        }
+
+        Some(ring)
+    }
+
+    fn in_order(&self) -> bool {
+        !self.written.is_null()
+    }
+
+    unsafe fn add(
+        &mut self,
+        buffers: impl Iterator<Item = Buffer>,
+        count: usize,
+        written: u32,
+    ) -> Option<u16> {
+        if count > 1 && count <= MAX_INDIRECT && !self.indirect.is_null() {
+            if self.num_free == 0 {
+                return None;
+            }
+            let head = self.add_indirect(buffers, count);
+            self.record_written(head, written);
+            self.publish(head);
+            return Some(head);
+        }
+
+        if count > self.num_free as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut id = head;
+        let mut last = head;
+
+        for (addr, len, flags) in buffers {
+            let d = self.desc_ptr(id);
+            let next = read_volatile(addr_of!((*d).next));
+
+            write_volatile(addr_of_mut!((*d).addr), addr);
+            write_volatile(addr_of_mut!((*d).len), len);
+            write_volatile(addr_of_mut!((*d).flags), flags | VIRTQ_DESC_F_NEXT);
+
+            last = id;
+            id = next;
+        }
+
+        // Terminate the chain; the rest of the free list starts at `id`.
+        let d = self.desc_ptr(last);
+        let flags = read_volatile(addr_of!((*d).flags));
+        write_volatile(addr_of_mut!((*d).flags), flags & !VIRTQ_DESC_F_NEXT);
+
+        self.free_head = id;
+        self.num_free -= count as u16;
+
+        self.record_written(head, written);
+        self.publish(head);
+        Some(head)
+    }
+
+    unsafe fn record_written(&mut self, head: u16, written: u32) {
+        if self.in_order() {
+            *self.written.add(head as usize) = written;
+        }
+    }
+
+    /// Fills the indirect table of the next free descriptor with `count`
+    /// buffers and points that descriptor at it.
+    unsafe fn add_indirect(&mut self, buffers: impl Iterator<Item = Buffer>, count: usize) -> u16 {
+        let head = self.free_head;
+        let table = self.indirect.add(head as usize * MAX_INDIRECT);
+
+        for (i, (addr, len, flags)) in buffers.enumerate() {
+            let next_flag = if i + 1 < count { VIRTQ_DESC_F_NEXT } else { 0 };
+            table.add(i).write_volatile(VirtqDesc {
+                addr,
+                len,
+                flags: flags | next_flag,
+                next: (i + 1) as u16,
+            });
+        }
+
+        let d = self.desc_ptr(head);
+        self.free_head = read_volatile(addr_of!((*d).next));
+        self.num_free -= 1;
+
+        write_volatile(addr_of_mut!((*d).addr), table as u64);
+        write_volatile(addr_of_mut!((*d).len), (count * size_of::<VirtqDesc>()) as u32);
+        write_volatile(addr_of_mut!((*d).flags), VIRTQ_DESC_F_INDIRECT);
+
+        head
+    }
+
+    /// Places a chain head in the avail ring and bumps `avail.idx`.
+    unsafe fn publish(&mut self, head: u16) {
+        let idx = read_volatile(addr_of!((*self.avail).idx));
+        write_volatile(self.avail_ring_ptr(idx % self.size), head);
+
+        // Descriptor and ring entry writes before the idx update
+        dmb_ishst();
+
+        write_volatile(addr_of_mut!((*self.avail).idx), idx.wrapping_add(1));
+    }
+
+    /// With EVENT_IDX, whether `avail_event` was crossed since the last
+    /// kick; otherwise whether VIRTQ_USED_F_NO_NOTIFY is clear.
+    fn kick_needed(&mut self) -> bool {
+        unsafe {
+            let new = read_volatile(addr_of!((*self.avail).idx));
+            let old = self.kicked_idx;
+            self.kicked_idx = new;
+
+            if self.event_idx {
+                need_event(read_volatile(self.avail_event_ptr()), new, old)
+            } else {
+                read_volatile(addr_of!((*self.used).flags)) & VIRTQ_USED_F_NO_NOTIFY == 0
+            }
+        }
+    }
+
+    fn disable_interrupts(&mut self) {
+        if !self.event_idx {
+            unsafe {
+                write_volatile(addr_of_mut!((*self.avail).flags), VIRTQ_AVAIL_F_NO_INTERRUPT);
+            }
+        }
+        // With EVENT_IDX, `used_event` is simply no longer advanced.
+    }
+
+    fn enable_interrupts(&mut self) {
+        unsafe {
+            if self.event_idx {
+                write_volatile(self.used_event_ptr(), self.last_used_idx);
+            } else {
+                write_volatile(addr_of_mut!((*self.avail).flags), 0);
+            }
+        }
+    }
+
+    fn has_used(&self) -> bool {
+        // SAFETY: `used` points at the live ring for the queue's lifetime.
+        self.batch.is_some()
+            || unsafe { read_volatile(addr_of!((*self.used).idx)) != self.last_used_idx }
+    }
+
+    unsafe fn pop_used(&mut self, interrupts: bool) -> (u16, u32) {
+        let (id, len) = match self.batch {
+            Some(batch) => batch,
+            None => self.read_used(interrupts),
+        };
+        if !self.in_order() {
+            self.free_chain(id);
+            return (id, len);
+        }
+
+        // Everything up to `id` is complete; hand back the oldest head.
+        let oldest = (self.free_head + self.num_free) % self.size;
+        self.free_chain(oldest);
+        if oldest == id {
+            self.batch = None;
+            (oldest, len)
+        } else {
+            self.batch = Some((id, len));
+            (oldest, *self.written.add(oldest as usize))
+        }
+    }
+
+    unsafe fn read_used(&mut self, interrupts: bool) -> (u16, u32) {
+        let slot = self.last_used_idx % self.size;
+        let elem = self.used_elem_ptr(slot);
+        let id = read_volatile(addr_of!((*elem).id)) as u16;
+        let len = read_volatile(addr_of!((*elem).len));
+
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        if self.event_idx && interrupts {
+            // Ask for an interrupt when the next entry is used.
+            write_volatile(self.used_event_ptr(), self.last_used_idx);
+        }
+
+        (id, len)
+    }
+
+    unsafe fn free_chain(&mut self, head: u16) {
+        let mut id = head;
+        loop {
+            let d = self.desc_ptr(id);
+            let flags = read_volatile(addr_of!((*d).flags));
+            let next = read_volatile(addr_of!((*d).next));
+
+            self.num_free += 1;
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                if self.in_order() {
+                    // Already in place behind the free descriptors.
+                    return;
+                }
+                // Splice the whole chain onto the front of the free list.
+                write_volatile(addr_of_mut!((*d).next), self.free_head);
+                break;
+            }
+            id = next;
+        }
+        self.free_head = head;
+    }
+
+    unsafe fn desc_ptr(&self, id: u16) -> *mut VirtqDesc {
+        self.desc.add(id as usize)
+    }
+
+    unsafe fn avail_ring_ptr(&self, slot: u16) -> *mut u16 {
+        (self.avail as *mut u8).add(RING_HEADER_SIZE + slot as usize * 2) as *mut u16
+    }
+
+    unsafe fn used_elem_ptr(&self, slot: u16) -> *const VirtqUsedElem {
+        (self.used as *const u8)
+            .add(RING_HEADER_SIZE + slot as usize * size_of::<VirtqUsedElem>())
+            as *const VirtqUsedElem
+    }
+
+    /// `used_event`, just past the avail ring: the driver wants an
+    /// interrupt once the device's used idx moves past it.
+    unsafe fn used_event_ptr(&self) -> *mut u16 {
+        self.avail_ring_ptr(self.size)
+    }
+
+    /// `avail_event`, just past the used ring: the device wants a
+    /// notification once the avail idx moves past it.
+    unsafe fn avail_event_ptr(&self) -> *const u16 {
+        self.used_elem_ptr(self.size) as *const u16
+    }
+}
+
+/// vring_need_event() from the virtio spec: true if `event` lies in the
+/// window of indices published since the last notification, `old..new`.
+pub fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+// ---------------- BARRIERS ----------------
+//
+// The device is another observer in the inner shareable domain (QEMU
+// maps guest RAM coherently), so `dmb ish*` is enough for DMA ordering.
+
+#[inline(always)]
+pub fn dmb_ish() {
+    unsafe { asm!("dmb ish", options(nostack, preserves_flags)); }
+}
+
+#[inline(always)]
+pub fn dmb_ishst() {
+    unsafe { asm!("dmb ishst", options(nostack, preserves_flags)); }
+}
+
+#[inline(always)]
+pub fn dmb_ishld() {
+    unsafe { asm!("dmb ishld", options(nostack, preserves_flags)); }
+}
@@ -0,0 +1,171 @@
+use crate::arch::aarch64::{get_current_time_ms, rng};
+use crate::drivers::allocator;
+use crate::drivers::virtio::{
+    register_device,
+    DeviceInfo,
+    Features,
+    Transport,
+    VIRTIO_F_IN_ORDER,
+    VIRTIO_F_RING_PACKED,
+    VIRTIO_F_VERSION_1,
+};
+use crate::drivers::virtio_pci::{self, VirtioPciTransport, VIRTIO_PCI_VENDOR};
+use crate::drivers::virtio_queue::VirtQueue;
+use crate::pci::core::{PciDevice, PciDriver, PciMatch};
+
+//
+// =======================
+//  VIRTIO RNG CONSTANTS
+// =======================
+//
+
+pub const DEVICE_ID_RNG: u16 = 4;
+
+const REQUEST_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 8;
+
+/// Bytes mixed into the DRBG per reseed: one full ChaCha20 key.
+const SEED_LEN: usize = 32;
+
+/// How long `reseed` waits for the device before giving up.
+const TIMEOUT_MS: u64 = 100;
+
+const REQUIRED_FEATURES: Features = VIRTIO_F_VERSION_1;
+
+const OPTIONAL_FEATURES: Features = VIRTIO_F_RING_PACKED.with(VIRTIO_F_IN_ORDER);
+
+//
+// =======================
+//  PCI DRIVER
+// =======================
+//
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "virtio-rng",
+    ids: &[
+        PciMatch::id(VIRTIO_PCI_VENDOR, virtio_pci::transitional_id(DEVICE_ID_RNG)),
+        PciMatch::id(VIRTIO_PCI_VENDOR, virtio_pci::modern_id(DEVICE_ID_RNG)),
+    ],
+    probe: probe_pci,
+};
+
+/// The entropy device bound by `PCI_DRIVER`.
+static mut PCI_DEVICE: Option<VirtioRng> = None;
+
+pub struct VirtioRng {
+    transport: VirtioPciTransport,
+    queue: VirtQueue,
+    /// Device-writable request buffer of `SEED_LEN` bytes.
+    buf: *mut u8,
+}
+
+fn probe_pci(dev: &PciDevice) -> bool {
+    let slot = unsafe { &mut *core::ptr::addr_of_mut!(PCI_DEVICE) };
+    if slot.is_some() {
+        warn!("virtio-rng: only one device is supported");
+        return false;
+    }
+
+    // SAFETY: the enumerator assigned and enabled the BARs.
+    let Some(transport) = (unsafe { VirtioPciTransport::probe(dev) }) else {
+        return false;
+    };
+    *slot = unsafe { VirtioRng::new(transport) };
+    if slot.is_none() {
+        return false;
+    }
+
+    reseed();
+    true
+}
+
+/// Mixes fresh bytes from the device into the kernel DRBG. Returns false
+/// if there is no device or it did not answer in time.
+pub fn reseed() -> bool {
+    let Some(dev) = (unsafe { (*core::ptr::addr_of_mut!(PCI_DEVICE)).as_mut() }) else {
+        return false;
+    };
+
+    let mut seed = [0u8; SEED_LEN];
+    let Some(len) = (unsafe { dev.read(&mut seed) }) else {
+        warn!("virtio-rng: no entropy from the device");
+        return false;
+    };
+
+    rng::reseed(&seed[..len], rng::EntropySource::VirtioRng);
+    info!("virtio-rng: DRBG reseeded with {} bytes", len);
+    true
+}
+
+impl VirtioRng {
+
+    unsafe fn new(mut transport: VirtioPciTransport) -> Option<Self> {
+        let features = match transport.negotiate(REQUIRED_FEATURES, OPTIONAL_FEATURES) {
+            Ok(features) => features,
+            Err(e) => {
+                error!("virtio-rng: {}", e);
+                return None;
+            }
+        };
+
+        let mut queue = VirtQueue::new(&mut transport, REQUEST_QUEUE, QUEUE_SIZE, features)?;
+
+        // Requests are completed by polling.
+        queue.disable_interrupts();
+
+        let buf = allocator::allocate_aligned(SEED_LEN, 16);
+        if buf.is_null() {
+            error!("virtio-rng: out of DMA memory");
+            return None;
+        }
+
+        transport.driver_ok();
+        register_device(DeviceInfo {
+            name: "virtio-rng",
+            features,
+            feature_names: &[],
+        });
+
+        Some(Self { transport, queue, buf })
+    }
+
+    /// Fills `out` from the device, one request at a time. Returns the
+    /// number of bytes read, or `None` if the device returned none.
+    unsafe fn read(&mut self, out: &mut [u8]) -> Option<usize> {
+        if self.buf.is_null() {
+            return None;
+        }
+        let mut filled = 0;
+        let deadline = get_current_time_ms() + TIMEOUT_MS;
+
+        while filled < out.len() {
+            let want = (out.len() - filled).min(SEED_LEN);
+            let request = core::slice::from_raw_parts_mut(self.buf, want);
+            let token = self.queue.add(&[], &mut [request])?;
+            self.queue.kick(&self.transport);
+
+            // The buffer is reused, so wait for this request to complete.
+            let len = loop {
+                match self.queue.pop_used() {
+                    Some((done, len)) if done == token => break len as usize,
+                    Some((done, _)) => warn!("virtio-rng: unexpected completion {}", done),
+                    None if get_current_time_ms() > deadline => {
+                        // The device still owns the buffer; stop using it.
+                        self.buf = core::ptr::null_mut();
+                        return (filled > 0).then_some(filled);
+                    }
+                    None => core::hint::spin_loop(),
+                }
+            };
+            if len == 0 {
+                break;
+            }
+
+            let len = len.min(want);
+            core::ptr::copy_nonoverlapping(self.buf, out[filled..].as_mut_ptr(), len);
+            filled += len;
+        }
+
+        (filled > 0).then_some(filled)
+    }
+}
@@ -0,0 +1,279 @@
+//! Minimal read-only flattened device tree (FDT) parser.
+//!
+//! Only what the kernel needs at boot: locate a node by path and read
+//! its properties. The blob is never copied or modified.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE:   u32 = 0x2;
+const FDT_PROP:       u32 = 0x3;
+const FDT_NOP:        u32 = 0x4;
+const FDT_END:        u32 = 0x9;
+
+/// QEMU places the DTB at the start of RAM for non-Linux kernels.
+const QEMU_VIRT_DTB_ADDR: usize = 0x4000_0000;
+
+static DTB_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Copy, Clone)]
+pub struct Fdt {
+    blob: &'static [u8],
+    struct_off: usize,
+    struct_end: usize,
+    strings_off: usize,
+}
+
+#[derive(Copy, Clone)]
+pub struct Node {
+    fdt: Fdt,
+    /// Offset of the node's name, just past its FDT_BEGIN_NODE token.
+    name_off: usize,
+    /// Offset of the first token after the name.
+    body_off: usize,
+}
+
+/// Records the DTB location handed over by the loader in x0, falling
+/// back to the QEMU virt default when x0 does not point at a valid blob.
+pub fn init(boot_x0: usize) -> bool {
+    for addr in [boot_x0, QEMU_VIRT_DTB_ADDR] {
+        // SAFETY: both candidates are in RAM mapped 1:1 (MMU off); only
+        // the 4-byte magic is read before the header is trusted.
+        if addr != 0 && unsafe { Fdt::from_addr(addr) }.is_some() {
+            DTB_ADDR.store(addr, Ordering::Relaxed);
+            return true;
+        }
+    }
+    false
+}
+
+/// The boot DTB, if `init` found one.
+pub fn get() -> Option<Fdt> {
+    match DTB_ADDR.load(Ordering::Relaxed) {
+        0 => None,
+        // SAFETY: validated by `init`.
+        addr => unsafe { Fdt::from_addr(addr) },
+    }
+}
+
+/// `/chosen/bootargs`, as passed with QEMU's `-append`.
+pub fn bootargs() -> Option<&'static str> {
+    get()?.find_node("/chosen")?.property_str("bootargs")
+}
+
+/// Value of a `key=value` entry on the kernel command line.
+pub fn bootarg(key: &str) -> Option<&'static str> {
+    bootargs()?
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix(key)?.strip_prefix('='))
+}
+
+fn be32(bytes: &[u8], off: usize) -> Option<u32> {
+    let b = bytes.get(off..off + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+/// Returns the NUL-terminated string starting at `off`.
+fn cstr(bytes: &[u8], off: usize) -> Option<&str> {
+    let rest = bytes.get(off..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+impl Fdt {
+    /// # Safety
+    /// `addr` must point at readable memory that stays valid for the
+    /// lifetime of the kernel.
+    pub unsafe fn from_addr(addr: usize) -> Option<Self> {
+        let header = core::slice::from_raw_parts(addr as *const u8, 40);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+
+        let total = be32(header, 4)? as usize;
+        let blob = core::slice::from_raw_parts(addr as *const u8, total);
+
+        let struct_off = be32(blob, 8)? as usize;
+        let strings_off = be32(blob, 12)? as usize;
+        let struct_size = be32(blob, 36)? as usize;
+
+        if struct_off + struct_size > total || strings_off > total {
+            return None;
+        }
+
+        Some(Self {
+            blob,
+            struct_off,
+            struct_end: struct_off + struct_size,
+            strings_off,
+        })
+    }
+
+    pub fn root(&self) -> Option<Node> {
+        let mut off = self.struct_off;
+        loop {
+            match be32(self.blob, off)? {
+                FDT_NOP => off += 4,
+                FDT_BEGIN_NODE => return self.node_at(off + 4),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Looks up a node by absolute path, e.g. `/chosen` or `/pcie@10000000`.
+    /// A path component without a unit address also matches a node that
+    /// has one (`/pcie` finds `pcie@10000000`).
+    pub fn find_node(&self, path: &str) -> Option<Node> {
+        let mut node = self.root()?;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            node = node.children().find(|child| {
+                let name = child.name();
+                name == component
+                    || (!component.contains('@')
+                        && name.split('@').next() == Some(component))
+            })?;
+        }
+        Some(node)
+    }
+
+    fn node_at(&self, name_off: usize) -> Option<Node> {
+        let name = cstr(self.blob, name_off)?;
+        Some(Node {
+            fdt: *self,
+            name_off,
+            body_off: align4(name_off + name.len() + 1),
+        })
+    }
+
+    fn string_at(&self, nameoff: u32) -> Option<&'static str> {
+        cstr(self.blob, self.strings_off + nameoff as usize)
+    }
+
+    /// Reads the token at `off`, returning it and the offset of the next one.
+    fn token(&self, off: usize) -> Option<(Token, usize)> {
+        if off >= self.struct_end {
+            return None;
+        }
+        match be32(self.blob, off)? {
+            FDT_BEGIN_NODE => {
+                let name = cstr(self.blob, off + 4)?;
+                Some((Token::BeginNode(off + 4), align4(off + 4 + name.len() + 1)))
+            }
+            FDT_END_NODE => Some((Token::EndNode, off + 4)),
+            FDT_PROP => {
+                let len = be32(self.blob, off + 4)? as usize;
+                let nameoff = be32(self.blob, off + 8)?;
+                let value = self.blob.get(off + 12..off + 12 + len)?;
+                Some((Token::Prop(nameoff, value), align4(off + 12 + len)))
+            }
+            FDT_NOP => Some((Token::Nop, off + 4)),
+            FDT_END => None,
+            _ => None,
+        }
+    }
+}
+
+enum Token {
+    BeginNode(usize),
+    EndNode,
+    Prop(u32, &'static [u8]),
+    Nop,
+}
+
+impl Node {
+    /// Node name including the unit address, e.g. `pl011@9000000`.
+    pub fn name(&self) -> &'static str {
+        cstr(self.fdt.blob, self.name_off).unwrap_or("")
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'static [u8]> {
+        let mut off = self.body_off;
+        loop {
+            let (token, next) = self.fdt.token(off)?;
+            match token {
+                Token::Prop(nameoff, value) => {
+                    if self.fdt.string_at(nameoff)? == name {
+                        return Some(value);
+                    }
+                }
+                Token::Nop => {}
+                // Properties always precede subnodes.
+                Token::BeginNode(_) | Token::EndNode => return None,
+            }
+            off = next;
+        }
+    }
+
+    /// A string property, without its NUL terminator.
+    pub fn property_str(&self, name: &str) -> Option<&'static str> {
+        let value = self.property(name)?;
+        let len = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+        core::str::from_utf8(&value[..len]).ok()
+    }
+
+    /// Direct children of this node.
+    pub fn children(&self) -> Children {
+        Children {
+            fdt: self.fdt,
+            off: self.body_off,
+            done: false,
+        }
+    }
+}
+
+pub struct Children {
+    fdt: Fdt,
+    off: usize,
+    done: bool,
+}
+
+impl Iterator for Children {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let Some((token, next)) = self.fdt.token(self.off) else {
+                self.done = true;
+                return None;
+            };
+
+            match token {
+                Token::Prop(..) | Token::Nop => self.off = next,
+                Token::EndNode => {
+                    self.done = true;
+                    return None;
+                }
+                Token::BeginNode(name_off) => {
+                    // Skip over the whole subtree so the next call
+                    // resumes at this child's next sibling.
+                    let mut depth = 1;
+                    let mut off = next;
+                    while depth > 0 {
+                        let Some((t, n)) = self.fdt.token(off) else {
+                            self.done = true;
+                            break;
+                        };
+                        match t {
+                            Token::BeginNode(_) => depth += 1,
+                            Token::EndNode => depth -= 1,
+                            _ => {}
+                        }
+                        off = n;
+                    }
+                    self.off = off;
+                    return self.fdt.node_at(name_off);
+                }
+            }
+        }
+    }
+}
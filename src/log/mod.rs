@@ -0,0 +1,492 @@
+//! Kernel logging: `print!`/`println!` and leveled `log!` macros.
+//!
+//! Every line goes to the UART and into an in-memory ring. The ring
+//! lives in its own `.klog` section that the boot code does not clear,
+//! so after a warm reset the previous boot's log (and whether it ended
+//! in a panic) can still be read back.
+//!
+//! Per-module levels come from the `log=` boot argument, e.g.
+//! `log=info,pci=trace,drivers::virtio_pci=debug`: an entry without a
+//! module sets the default level, the others match module paths by
+//! prefix (crate name omitted).
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::arch::aarch64::{get_current_time_ms, irq_restore, irq_save};
+use crate::drivers::uart;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Level> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn from_u8(v: u8) -> Level {
+        match v {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+// ---------------- LEVEL FILTERS ----------------
+
+const MAX_FILTERS: usize = 16;
+const FILTER_NAME_MAX: usize = 32;
+
+#[derive(Copy, Clone)]
+struct Filter {
+    name: [u8; FILTER_NAME_MAX],
+    len: usize,
+    level: Level,
+}
+
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+static mut FILTERS: [Filter; MAX_FILTERS] = [Filter {
+    name: [0; FILTER_NAME_MAX],
+    len: 0,
+    level: Level::Info,
+}; MAX_FILTERS];
+static mut FILTER_COUNT: usize = 0;
+
+/// Strips the crate name from a `module_path!()`; the crate root is `main`.
+fn local_path(module: &str) -> &str {
+    match module.find("::") {
+        Some(i) => &module[i + 2..],
+        None => "main",
+    }
+}
+
+/// `filter` matches `module` itself and everything below it.
+fn filter_matches(filter: &str, module: &str) -> bool {
+    module.starts_with(filter)
+        && (module.len() == filter.len() || module[filter.len()..].starts_with("::"))
+}
+
+/// Level in effect for `module` (a `module_path!()`): the longest
+/// matching filter wins, otherwise the default.
+pub fn level_for(module: &str) -> Level {
+    let module = local_path(module);
+    let mut best: Option<(usize, Level)> = None;
+
+    let daif = irq_save();
+    unsafe {
+        let filters = &*core::ptr::addr_of!(FILTERS);
+        for f in &filters[..FILTER_COUNT] {
+            let name = core::str::from_utf8(&f.name[..f.len]).unwrap_or("");
+            if filter_matches(name, module) && best.is_none_or(|(len, _)| f.len > len) {
+                best = Some((f.len, f.level));
+            }
+        }
+    }
+    irq_restore(daif);
+
+    match best {
+        Some((_, level)) => level,
+        None => Level::from_u8(DEFAULT_LEVEL.load(Ordering::Relaxed)),
+    }
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    level <= level_for(module)
+}
+
+pub fn default_level() -> Level {
+    Level::from_u8(DEFAULT_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Sets the level for `module` (e.g. `pci` or `drivers::virtio_pci`),
+/// or the default level when `module` is `None`. Returns false when
+/// the filter table is full or the name is too long.
+pub fn set_level(module: Option<&str>, level: Level) -> bool {
+    let Some(module) = module else {
+        DEFAULT_LEVEL.store(level as u8, Ordering::Relaxed);
+        return true;
+    };
+
+    if module.len() > FILTER_NAME_MAX {
+        return false;
+    }
+
+    let daif = irq_save();
+    let ok = unsafe {
+        let filters = &mut *core::ptr::addr_of_mut!(FILTERS);
+        let count = FILTER_COUNT;
+
+        match filters[..count]
+            .iter()
+            .position(|f| &f.name[..f.len] == module.as_bytes())
+        {
+            Some(i) => {
+                filters[i].level = level;
+                true
+            }
+            None if count < MAX_FILTERS => {
+                let f = &mut filters[count];
+                f.name[..module.len()].copy_from_slice(module.as_bytes());
+                f.len = module.len();
+                f.level = level;
+                FILTER_COUNT = count + 1;
+                true
+            }
+            None => false,
+        }
+    };
+    irq_restore(daif);
+    ok
+}
+
+/// Calls `f` with every per-module filter (for the shell).
+pub fn for_each_filter(mut f: impl FnMut(&str, Level)) {
+    unsafe {
+        let filters = &*core::ptr::addr_of!(FILTERS);
+        for filter in &filters[..FILTER_COUNT] {
+            f(core::str::from_utf8(&filter.name[..filter.len]).unwrap_or(""), filter.level);
+        }
+    }
+}
+
+/// Applies the `log=` entry of the kernel command line.
+fn apply_bootargs(bootargs: &str) {
+    let Some(spec) = bootargs
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix("log="))
+    else {
+        return;
+    };
+
+    for entry in spec.split(',') {
+        match entry.split_once('=') {
+            Some((module, level)) => {
+                if let Some(level) = Level::parse(level) {
+                    set_level(Some(module), level);
+                }
+            }
+            None => {
+                if let Some(level) = Level::parse(entry) {
+                    set_level(None, level);
+                }
+            }
+        }
+    }
+}
+
+// ---------------- LOG RING ----------------
+
+pub const RING_SIZE: usize = 64 * 1024;
+const RING_MAGIC: u64 = 0x4B4C_4F47_5249_4E47; // "KLOGRING"
+
+#[repr(C, align(4096))]
+struct LogRing {
+    magic: u64,
+    /// Total bytes ever written; the ring holds the last `RING_SIZE`.
+    head: AtomicUsize,
+    /// Set by the panic handler, reported and cleared on the next boot.
+    panicked: AtomicUsize,
+    data: [u8; RING_SIZE],
+}
+
+/// Not part of .bss (see linker.ld), so its contents survive a warm reset.
+#[link_section = ".klog"]
+static mut KLOG: LogRing = LogRing {
+    magic: 0,
+    head: AtomicUsize::new(0),
+    panicked: AtomicUsize::new(0),
+    data: [0; RING_SIZE],
+};
+
+fn ring() -> &'static LogRing {
+    unsafe { &*core::ptr::addr_of!(KLOG) }
+}
+
+/// Appends to the ring. Writers reserve space with a single atomic add,
+/// so concurrent writers never overlap; a reader racing a writer may see
+/// a partially copied line, which is acceptable for a diagnostic log.
+fn ring_write(bytes: &[u8]) {
+    let ring = ring();
+    let start = ring.head.fetch_add(bytes.len(), Ordering::AcqRel);
+    let data = unsafe { core::ptr::addr_of_mut!(KLOG.data) as *mut u8 };
+    for (i, &b) in bytes.iter().enumerate() {
+        unsafe {
+            data.add((start + i) % RING_SIZE).write_volatile(b);
+        }
+    }
+}
+
+/// Copies the newest `out.len()` (or fewer) bytes of the log into `out`,
+/// oldest first. Returns the number of bytes copied.
+pub fn read_tail(out: &mut [u8]) -> usize {
+    let ring = ring();
+    let head = ring.head.load(Ordering::Acquire);
+    let available = head.min(RING_SIZE);
+    let n = available.min(out.len());
+    let start = head - n;
+
+    let data = unsafe { core::ptr::addr_of!(KLOG.data) as *const u8 };
+    for (i, slot) in out[..n].iter_mut().enumerate() {
+        *slot = unsafe { data.add((start + i) % RING_SIZE).read_volatile() };
+    }
+    n
+}
+
+/// Marks the ring so the next boot knows this one ended in a panic.
+pub fn mark_panic() {
+    ring().panicked.store(1, Ordering::SeqCst);
+}
+
+/// Validates (or resets) the ring, applies boot-argument filters and
+/// reports a panic left behind by the previous boot.
+pub fn init(bootargs: Option<&str>) {
+    let previous_panic = unsafe {
+        let klog = &mut *core::ptr::addr_of_mut!(KLOG);
+        if klog.magic != RING_MAGIC {
+            klog.head.store(0, Ordering::SeqCst);
+            klog.panicked.store(0, Ordering::SeqCst);
+            klog.magic = RING_MAGIC;
+            false
+        } else {
+            klog.panicked.swap(0, Ordering::SeqCst) != 0
+        }
+    };
+
+    if let Some(args) = bootargs {
+        apply_bootargs(args);
+    }
+
+    if previous_panic {
+        dump_previous_panic();
+    }
+
+    ring_write(b"---- boot ----\n");
+}
+
+/// Prints the tail of the log left by a boot that panicked.
+fn dump_previous_panic() {
+    const TAIL: usize = 2048;
+    let mut tail = [0u8; TAIL];
+    let n = read_tail(&mut tail);
+
+    // Start at a line boundary.
+    let start = tail[..n].iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+
+    uart::puts("---- previous boot panicked, last log lines: ----\n");
+    if let Ok(s) = core::str::from_utf8(&tail[start..n]) {
+        uart::puts(s);
+    }
+    uart::puts("---- end of previous boot log ----\n");
+}
+
+// ---------------- OUTPUT ----------------
+
+const LINE_MAX: usize = 256;
+
+/// Formats one line on the stack so it reaches the UART (whose `puts` is
+/// atomic against IRQ handlers) and the ring in a single piece.
+struct LineBuf {
+    buf: [u8; LINE_MAX],
+    len: usize,
+    /// Whether the text written so far, including any part that did not
+    /// fit, ends with a newline.
+    newline: bool,
+}
+
+impl LineBuf {
+    const fn new() -> Self {
+        Self { buf: [0; LINE_MAX], len: 0, newline: false }
+    }
+
+    /// Makes the buffer end with '\n', replacing the last character
+    /// (which may be multi-byte) if it is full.
+    fn end_line(&mut self) {
+        if self.buf[..self.len].ends_with(b"\n") {
+            return;
+        }
+        if self.len < LINE_MAX {
+            self.buf[self.len] = b'\n';
+            self.len += 1;
+            return;
+        }
+        let mut end = LINE_MAX - 1;
+        while end > 0 && self.buf[end] & 0xC0 == 0x80 {
+            end -= 1;
+        }
+        self.buf[end] = b'\n';
+        self.len = end + 1;
+    }
+
+    fn as_str(&self) -> &str {
+        // Truncation in write_str only happens at char boundaries.
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !s.is_empty() {
+            self.newline = s.ends_with('\n');
+        }
+        let space = LINE_MAX - self.len;
+        let mut n = s.len().min(space);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn emit(line: &LineBuf) {
+    uart::puts(line.as_str());
+    ring_write(line.as_str().as_bytes());
+}
+
+fn cpu_id() -> u64 {
+    let mpidr: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, mpidr_el1", out(reg) mpidr);
+    }
+    mpidr & 0xFF
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let mut line = LineBuf::new();
+    let _ = line.write_fmt(args);
+    // A truncated println! still ends its line.
+    if line.newline {
+        line.end_line();
+    }
+    emit(&line);
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    let ms = get_current_time_ms();
+    let mut line = LineBuf::new();
+    let _ = write!(
+        line,
+        "[{:>5}.{:03}] cpu{} {:<5} {}: ",
+        ms / 1000,
+        ms % 1000,
+        cpu_id(),
+        level.as_str(),
+        local_path(module),
+    );
+    let _ = line.write_fmt(args);
+    line.end_line();
+    emit(&line);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::log::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::log::_print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {{
+        let level = $level;
+        if $crate::log::enabled(level, module_path!()) {
+            $crate::log::_log(level, module_path!(), format_args!($($arg)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Debug, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Trace, $($arg)*) };
+}
+
+// ---------------- SHELL ----------------
+
+pub static SHELL_COMMANDS: &[crate::shell::Command] = &[
+    crate::shell::Command {
+        name: "log",
+        help: "log [level [module] <lvl>]  show or set log levels",
+        run: log_command,
+    },
+];
+
+fn log_command(args: &[&str]) {
+    match args {
+        [_] => {
+            println!("default: {}", default_level().as_str());
+            for_each_filter(|module, level| println!("{}: {}", module, level.as_str()));
+        }
+        [_, "level", level] | [_, "level", _, level] => {
+            let Some(level) = Level::parse(level) else {
+                println!("unknown level '{}' (error, warn, info, debug, trace)", level);
+                return;
+            };
+            let module = if args.len() == 4 { Some(args[2]) } else { None };
+            if !set_level(module, level) {
+                println!("filter table full or module name too long");
+            }
+        }
+        _ => println!("usage: log [level [module] <error|warn|info|debug|trace>]"),
+    }
+}
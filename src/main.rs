@@ -1,26 +1,44 @@
 #![no_std]
 #![no_main]
 
+#[macro_use]
+mod log;
+
 mod arch;
 mod drivers;
+mod dtb;
 mod gfx;
+mod net;
 mod pci;
+mod shell;
 
 use drivers::uart;
 use core::arch::asm;
 
 use pci::host::qemu_virt::QemuVirtPci;
 use pci::core::enumerate;
-use drivers::virtio_pci::VirtioPciTransport;
+use drivers::virtio_net::VirtioNet;
 
 #[no_mangle]
-pub extern "C" fn kmain() {
+pub extern "C" fn kmain(dtb_ptr: usize) {
     uart::init();
     uart::puts("\x1B[2J\x1B[H");
-    uart::puts("====================================================\n");
-    uart::puts("       Aether EdgeCloud Monolithic Kernel v0.1      \n");
-    uart::puts("====================================================\n");
-    uart::puts("[CHECK] UART: Initialized\n");
+
+    // ---------------- LOGGING ----------------
+
+    let have_dtb = dtb::init(dtb_ptr);
+    log::init(dtb::bootargs());
+
+    println!("====================================================");
+    println!("       Aether EdgeCloud Monolithic Kernel v0.1      ");
+    println!("====================================================");
+    info!("UART initialized");
+
+    if have_dtb {
+        info!("DTB found, bootargs: \"{}\"", dtb::bootargs().unwrap_or(""));
+    } else {
+        warn!("No DTB found, using built-in defaults");
+    }
 
     // ---------------- CPU INFO ----------------
 
@@ -31,73 +49,74 @@ pub extern "C" fn kmain() {
         asm!("mrs {}, MIDR_EL1", out(reg) midr);
     }
 
-    uart::puts("[INFO] Current EL: ");
-    uart::putc_hex64(current_el >> 2);
-    uart::puts("\n[INFO] CPU ID (MIDR): ");
-    uart::putc_hex64(midr);
-    uart::puts("\n");
+    info!("Current EL: {}", current_el >> 2);
+    info!("CPU ID (MIDR): {:#018x}", midr);
+
+    // ---------------- ENTROPY ----------------
+
+    // Without RNDR the DRBG starts from counter jitter; a virtio-rng
+    // device found on PCI reseeds it.
+    arch::aarch64::rng::init();
+    if arch::aarch64::rng::source() == arch::aarch64::rng::EntropySource::Rndr {
+        info!("Entropy: RNDR (FEAT_RNG)");
+    }
 
     // ---------------- VBAR ----------------
 
-    uart::puts("[CHECK] Setting up Exception Vectors...\n");
     unsafe {
         extern "C" { static __vectors_el1: u8; }
         let vbar = &__vectors_el1 as *const u8 as u64;
         asm!("msr vbar_el1, {}", in(reg) vbar);
         asm!("isb");
     }
-    uart::puts("[OK] VBAR_EL1 set.\n");
+    info!("VBAR_EL1 set");
 
     // ---------------- GIC ----------------
 
-    uart::puts("[CHECK] Initializing GICv3...\n");
     drivers::gic::init();
-    uart::puts("[OK] GICv3 Ready.\n");
-
-    // ---------------- PCI INIT ----------------
-
-    uart::puts("[CHECK] Initializing PCI subsystem...\n");
+    info!("GICv3 ready");
 
-    unsafe {
-        let host = QemuVirtPci;
-
-        match enumerate(&host) {
+    uart::enable_interrupts();
+    info!("UART: interrupt-driven RX/TX (SPI 33)");
 
-            Some(info) => {
-                uart::puts("[MAIN] VirtIO PCI device discovered\n");
+    // ---------------- PCI INIT ----------------
 
-                // Build transport
-                let transport =
-                    VirtioPciTransport::new(
-                        info.common_cfg,
-                        info.notify_base,
-                        info.notify_off_multiplier,
-                    );
+    info!("Initializing PCI subsystem...");
 
-                // Create GPU device
-                match crate::drivers::virtio_gpu::device::VirtioGpu::new(transport) {
+    pci::core::register_driver(&drivers::virtio_gpu::device::PCI_DRIVER);
+    pci::core::register_driver(&drivers::virtio_net::PCI_DRIVER);
+    pci::core::register_driver(&drivers::virtio_blk::PCI_DRIVER);
+    pci::core::register_driver(&drivers::virtio_rng::PCI_DRIVER);
 
-                    Some(mut gpu) => {
-                        uart::puts("[MAIN] GPU driver initialized\n");
+    let count = unsafe { enumerate(&QemuVirtPci) };
+    info!("{} PCI function(s) found", count);
 
-                        gpu.init_display();
+    pci::core::probe_drivers();
 
-                        uart::puts("[MAIN] Display initialization complete\n");
-                    }
+    info!("PCI enumeration complete");
 
-                    None => {
-                        uart::puts("[MAIN] GPU driver failed to initialize\n");
-                    }
-                }
-            }
+    if arch::aarch64::rng::source() == arch::aarch64::rng::EntropySource::CounterJitter {
+        warn!("Entropy: DRBG seeded from counter jitter only (no RNDR or virtio-rng)");
+    }
 
-            None => {
-                uart::puts("[MAIN] No VirtIO PCI device found\n");
-            }
+    // ---------------- BENCHMARK ----------------
+
+    if dtb::bootarg("bench") == Some("virtio-net") {
+        match drivers::virtio_net::find() {
+            Some(transport) => match unsafe { VirtioNet::new(transport) } {
+                Some(mut net) => net.bench_tx(),
+                None => error!("bench: virtio-net failed to initialize"),
+            },
+            None => match drivers::virtio_net::pci_device() {
+                Some(net) => net.bench_tx(),
+                None => warn!("bench: no virtio-net device"),
+            },
         }
     }
 
-    uart::puts("[OK] PCI Enumeration Complete.\n");
+    // ---------------- NETWORK ----------------
+
+    net::init();
 
     // ---------------- ENABLE IRQ ----------------
 
@@ -112,37 +131,34 @@ pub extern "C" fn kmain() {
         asm!("msr cntv_ctl_el0, {}", in(reg) 1u64);
     }
 
-    uart::puts("\n--- Aether OS Ready (PCI Mode) ---\n");
+    println!("\n--- Aether OS Ready (PCI Mode) ---");
+
+    // ---------------- SHELL ----------------
+
+    shell::register(log::SHELL_COMMANDS);
+    shell::register(pci::SHELL_COMMANDS);
+    shell::register(drivers::virtio::SHELL_COMMANDS);
+    shell::init();
 
     // ---------------- MAIN LOOP ----------------
 
     loop {
-        unsafe { asm!("wfi"); }
+        shell::poll();
+        if !net::poll() {
+            unsafe { asm!("wfi"); }
+        }
     }
 }
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    uart::puts("\n!!!! KERNEL PANIC !!!!\n");
+    uart::enter_polling_mode();
+    log::mark_panic();
+
+    println!("\n!!!! KERNEL PANIC !!!!");
+    println!("{}", info.message());
     if let Some(location) = info.location() {
-        uart::puts("File: ");
-        uart::puts(location.file());
-        uart::puts(" Line: ");
-        put_decimal(location.line() as u64);
+        println!("File: {} Line: {}", location.file(), location.line());
     }
     loop { unsafe { asm!("wfe"); } }
 }
-
-fn put_decimal(mut n: u64) {
-    if n == 0 { uart::putc(b'0'); return; }
-    let mut buf = [0u8; 20];
-    let mut i = 0;
-    while n > 0 {
-        buf[i] = (n % 10) as u8 + b'0';
-        n /= 10;
-        i += 1;
-    }
-    for j in (0..i).rev() {
-        uart::putc(buf[j]);
-    }
-}
\ No newline at end of file
@@ -1,49 +1,49 @@
-use crate::drivers::virtio_queue::VirtQueue;
+use crate::arch::aarch64::{get_current_time_ms, rng};
+use crate::drivers::virtio::Transport;
+use crate::drivers::virtio_net::{self, RxQueue, TxQueue, VirtioNet};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet, SocketStorage};
 use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::tcp;
 use smoltcp::time::Instant;
-use core::sync::atomic::{self, Ordering};
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr, Ipv4Address};
 
 // --- 1. Struct Definitions ---
 
-pub struct VirtioNetDevice {
-    pub base: usize,
-    pub rx: VirtQueue,
-    pub tx: VirtQueue,
+pub struct VirtioRxToken<'a, T: Transport> {
+    pub queue: &'a mut RxQueue,
+    pub transport: &'a T,
 }
 
-pub struct VirtioRxToken<'a> {
-    pub queue: &'a mut VirtQueue,
-}
-
-pub struct VirtioTxToken<'a> {
-    pub queue: &'a mut VirtQueue,
-    pub base: usize,
+pub struct VirtioTxToken<'a, T: Transport> {
+    pub queue: &'a mut TxQueue,
+    pub transport: &'a T,
 }
 
 // --- 2. Device Trait Implementation ---
 
-impl smoltcp::phy::Device for VirtioNetDevice {
-    type RxToken<'a> = VirtioRxToken<'a> where Self: 'a;
-    type TxToken<'a> = VirtioTxToken<'a> where Self: 'a;
+impl<T: Transport> smoltcp::phy::Device for VirtioNet<T> {
+    type RxToken<'a> = VirtioRxToken<'a, T> where Self: 'a;
+    type TxToken<'a> = VirtioTxToken<'a, T> where Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        unsafe {
-            let used_idx = (*self.rx.used).idx;
-            if self.rx.last_used_idx != used_idx {
-                return Some((
-                    VirtioRxToken { queue: &mut self.rx },
-                    VirtioTxToken { queue: &mut self.tx, base: self.base }
-                ));
-            }
+        // The TX token must be usable too: smoltcp may answer the frame
+        // straight away (an ARP reply, a TCP ACK).
+        if !self.rx.has_packet() || !self.tx.has_room() {
+            return None;
         }
-        None
+        Some((
+            VirtioRxToken { queue: &mut self.rx, transport: &self.transport },
+            VirtioTxToken { queue: &mut self.tx, transport: &self.transport },
+        ))
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
         // If this doesn't print, smoltcp is blocking the transmit internally
-        // crate::drivers::uart::puts("[DEBUG] smoltcp requested TxToken\n");
-        crate::drivers::uart::puts("[DEBUG] transmit() requested\n");
-        Some(VirtioTxToken { queue: &mut self.tx, base: self.base })
+        trace!("transmit() requested");
+        if !self.tx.has_room() {
+            return None;
+        }
+        Some(VirtioTxToken { queue: &mut self.tx, transport: &self.transport })
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
@@ -56,89 +56,36 @@ impl smoltcp::phy::Device for VirtioNetDevice {
     }
 }
 
-// --- 3. Token Implementations (UNCHANGED) ---
+// --- 3. Token Implementations ---
 
-impl<'a> phy::RxToken for VirtioRxToken<'a> {
+impl<'a, T: Transport> phy::RxToken for VirtioRxToken<'a, T> {
     fn consume<R, F>(self, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        unsafe {
-            let last = self.queue.last_used_idx % self.queue.size;
-            let used_elem = &(*self.queue.used).ring[last as usize];
-            let desc_id = used_elem.id as u16;
-
-            let desc_ptr = self.queue.desc.add(desc_id as usize);
-            let data_ptr = ((*desc_ptr).addr + 12) as *mut u8;
-            let data_len = (used_elem.len - 12) as usize;
-
-            let slice = core::slice::from_raw_parts_mut(data_ptr, data_len);
-
-            if data_len > 0 {
-                crate::drivers::uart::puts("[DEBUG] RX packet consumed\n");
-            }
-
-            let result = f(slice);
-
-            // --- CRITICAL: RE-QUEUE THE DESCRIPTOR ---
-            let avail_slot = (*self.queue.avail).idx % self.queue.size;
-            (*self.queue.avail).ring[avail_slot as usize] = desc_id;
-
-            atomic::fence(Ordering::SeqCst);
+        // `receive` only hands out a token when a frame is pending.
+        let (token, frame) = self.queue.pop().expect("RX token without a packet");
 
-            (*self.queue.avail).idx = (*self.queue.avail).idx.wrapping_add(1);
+        if !frame.is_empty() {
+            trace!("RX packet consumed");
+        }
 
-            atomic::fence(Ordering::SeqCst);
+        let result = f(frame);
 
-            self.queue.last_used_idx = self.queue.last_used_idx.wrapping_add(1);
+        // --- CRITICAL: RE-QUEUE THE BUFFER ---
+        self.queue.recycle(token, self.transport);
 
-            result
-        }
+        result
     }
 }
 
-impl<'a> phy::TxToken for VirtioTxToken<'a> {
+impl<'a, T: Transport> phy::TxToken for VirtioTxToken<'a, T> {
     fn consume<R, F>(self, len: usize, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        unsafe {
-            // 1. Get the current descriptor we want to use. 
-            // For a simple driver, we can use descriptor 0, 
-            // but we must ensure the length is updated.
-            let desc_ptr = self.queue.desc.add(0);
-            (*desc_ptr).len = (len + 12) as u32; // Include VirtIO Header
-
-            // 2. Data starts after the 12-byte VirtIO header
-            let data_ptr = ((*desc_ptr).addr + 12) as *mut u8;
-            let slice = core::slice::from_raw_parts_mut(data_ptr, len);
-
-            // 3. Let smoltcp fill the buffer with the Ethernet frame
-            let result = f(slice);
-
-            // 4. Update the Available Ring
-            // We put descriptor 0 into the next available slot in the ring
-            let avail_idx = (*self.queue.avail).idx % self.queue.size;
-            (*self.queue.avail).ring[avail_idx as usize] = 0;
-
-            // --- CRITICAL: MEMORY BARRIER ---
-            // Ensure data is in RAM before updating the index
-            atomic::fence(Ordering::SeqCst);
-
-            // 5. Increment the Available Index to tell the device "1 new packet"
-            (*self.queue.avail).idx = (*self.queue.avail).idx.wrapping_add(1);
-
-            // --- CRITICAL: NOTIFY BARRIER ---
-            // Ensure index is updated before ringing the doorbell
-            atomic::fence(Ordering::SeqCst);
-
-            // 6. Ring the Doorbell for Queue 1 (TX)
-            crate::drivers::virtio_mmio::notify_queue(self.base, 1);
-
-            crate::drivers::uart::puts("[DEBUG] TX Packet pushed to VirtIO doorbell\n");
-            
-            result
-        }
+        // The driver prepends the virtio-net header and rings the doorbell.
+        self.queue.send(self.transport, len, f)
     }
 }
 
@@ -151,7 +98,14 @@ pub const APP_JS:     &[u8] = include_bytes!("../../webui/app.js");
 /// Simple container for an HTTP response
 pub struct Response {
     pub header: &'static [u8],
-    pub body: &'static [u8],
+    pub body: Body,
+}
+
+/// Where a response body comes from.
+pub enum Body {
+    Static(&'static [u8]),
+    /// The kernel log ring, copied out when the response is started.
+    Klogs,
 }
 
 /// Dispatches the correct file based on the HTTP request string
@@ -160,18 +114,24 @@ pub fn dispatch_request(request: &[u8]) -> Response {
     if contains(request, b"GET /style.css") {
         Response {
             header: b"HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nConnection: close\r\n\r\n",
-            body: STYLE_CSS,
+            body: Body::Static(STYLE_CSS),
         }
     } else if contains(request, b"GET /app.js") {
         Response {
             header: b"HTTP/1.1 200 OK\r\nContent-Type: application/javascript\r\nConnection: close\r\n\r\n",
-            body: APP_JS,
+            body: Body::Static(APP_JS),
+        }
+    } else if contains(request, b"GET /klogs") {
+        // Kernel log ring, polled by the dashboard's KLogs window
+        Response {
+            header: b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+            body: Body::Klogs,
         }
     } else {
         // Default to index.html for "/" or unknown paths
         Response {
             header: b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: close\r\n\r\n",
-            body: INDEX_HTML,
+            body: Body::Static(INDEX_HTML),
         }
     }
 }
@@ -198,4 +158,200 @@ pub fn get_request_path(request: &[u8]) -> &[u8] {
         end += 1;
     }
     &request[start..end]
-}
\ No newline at end of file
+}
+// --- 5. HTTP Server ---
+
+/// Address on QEMU's user-mode network, where the host reaches the guest
+/// through a `hostfwd` rule.
+const IP_ADDR: Ipv4Address = Ipv4Address::new(10, 0, 2, 15);
+const IP_PREFIX: u8 = 24;
+const GATEWAY: Ipv4Address = Ipv4Address::new(10, 0, 2, 2);
+
+/// Used when the device does not report a MAC (QEMU's default one).
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+const HTTP_PORT: u16 = 80;
+const REQUEST_MAX: usize = 1024;
+const TCP_BUF_LEN: usize = 8 * 1024;
+
+static mut SOCKET_STORAGE: [SocketStorage<'static>; 1] = [SocketStorage::EMPTY];
+static mut TCP_RX_BUF: [u8; TCP_BUF_LEN] = [0; TCP_BUF_LEN];
+static mut TCP_TX_BUF: [u8; TCP_BUF_LEN] = [0; TCP_BUF_LEN];
+static mut KLOGS_BUF: [u8; crate::log::RING_SIZE] = [0; crate::log::RING_SIZE];
+
+/// Progress of the connection on the listening socket.
+enum Stage {
+    /// Collecting the request headers.
+    Request,
+    /// Sending the header, then the body, `sent` bytes in.
+    Response { header: &'static [u8], body: Body, sent: usize },
+    /// Response sent and the socket closed; wait for it to wind down.
+    Done,
+}
+
+/// One-connection-at-a-time server for the web dashboard.
+struct HttpServer {
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    handle: SocketHandle,
+    stage: Stage,
+    request: [u8; REQUEST_MAX],
+    request_len: usize,
+    /// Copy of the log ring taken when a /klogs response starts, so the
+    /// body stays stable while it trickles out over several polls.
+    klogs: &'static mut [u8],
+    klogs_len: usize,
+}
+
+static mut SERVER: Option<HttpServer> = None;
+
+fn now() -> Instant {
+    Instant::from_millis(get_current_time_ms() as i64)
+}
+
+/// Brings up the interface on the PCI virtio-net device and starts
+/// listening for the dashboard.
+pub fn init() {
+    let Some(dev) = virtio_net::pci_device() else {
+        info!("net: no virtio-net device, web dashboard disabled");
+        return;
+    };
+    let slot = unsafe { &mut *core::ptr::addr_of_mut!(SERVER) };
+
+    let mac = dev.mac().unwrap_or(DEFAULT_MAC);
+    let mut config = Config::new(HardwareAddress::Ethernet(EthernetAddress(mac)));
+    let mut seed = [0u8; 8];
+    rng::fill_bytes(&mut seed);
+    config.random_seed = u64::from_le_bytes(seed);
+
+    let mut iface = Interface::new(config, dev, now());
+    iface.update_ip_addrs(|addrs| {
+        let _ = addrs.push(IpCidr::new(IpAddress::Ipv4(IP_ADDR), IP_PREFIX));
+    });
+    let _ = iface.routes_mut().add_default_ipv4_route(GATEWAY);
+
+    // SAFETY: `init` runs once; the buffers are only reached through the
+    // server from here on.
+    let storage: &'static mut [SocketStorage] = unsafe { &mut *core::ptr::addr_of_mut!(SOCKET_STORAGE) };
+    let rx: &'static mut [u8] = unsafe { &mut *core::ptr::addr_of_mut!(TCP_RX_BUF) };
+    let tx: &'static mut [u8] = unsafe { &mut *core::ptr::addr_of_mut!(TCP_TX_BUF) };
+    let klogs: &'static mut [u8] = unsafe { &mut *core::ptr::addr_of_mut!(KLOGS_BUF) };
+    let mut sockets = SocketSet::new(storage);
+    let handle = sockets.add(tcp::Socket::new(
+        tcp::SocketBuffer::new(rx),
+        tcp::SocketBuffer::new(tx),
+    ));
+
+    *slot = Some(HttpServer {
+        iface,
+        sockets,
+        handle,
+        stage: Stage::Request,
+        request: [0; REQUEST_MAX],
+        request_len: 0,
+        klogs,
+        klogs_len: 0,
+    });
+    info!("net: web dashboard on http://{}:{}/", IP_ADDR, HTTP_PORT);
+}
+
+/// Moves frames between the device and the stack and advances the
+/// dashboard connection. Returns true if anything happened, in which case
+/// the caller should poll again before waiting for an interrupt.
+pub fn poll() -> bool {
+    let server = unsafe { &mut *core::ptr::addr_of_mut!(SERVER) };
+    let (Some(server), Some(dev)) = (server.as_mut(), virtio_net::pci_device()) else {
+        return false;
+    };
+
+    let mut active = server.iface.poll(now(), dev, &mut server.sockets);
+    active |= server.serve();
+    active
+}
+
+impl HttpServer {
+    fn serve(&mut self) -> bool {
+        let socket = self.sockets.get_mut::<tcp::Socket>(self.handle);
+
+        if !socket.is_open() {
+            self.stage = Stage::Request;
+            self.request_len = 0;
+            if socket.listen(HTTP_PORT).is_err() {
+                warn!("net: cannot listen on port {}", HTTP_PORT);
+            }
+            return false;
+        }
+
+        match &mut self.stage {
+            Stage::Request => {
+                if socket.state() == tcp::State::CloseWait && !socket.can_recv() {
+                    // Peer hung up before finishing its request.
+                    socket.close();
+                    self.stage = Stage::Done;
+                    return true;
+                }
+                if !socket.can_recv() {
+                    return false;
+                }
+                let buf = &mut self.request[self.request_len..];
+                self.request_len += socket.recv_slice(buf).unwrap_or(0);
+
+                let request = &self.request[..self.request_len];
+                if !contains(request, b"\r\n\r\n") && self.request_len < REQUEST_MAX {
+                    return true;
+                }
+
+                debug!(
+                    "net: GET {}",
+                    core::str::from_utf8(get_request_path(request)).unwrap_or("?")
+                );
+                let response = dispatch_request(request);
+                if let Body::Klogs = response.body {
+                    self.klogs_len = snapshot_klogs(self.klogs);
+                }
+                self.stage = Stage::Response { header: response.header, body: response.body, sent: 0 };
+                true
+            }
+            Stage::Response { header, body, sent } => {
+                let body = match body {
+                    Body::Static(bytes) => *bytes,
+                    Body::Klogs => &self.klogs[..self.klogs_len],
+                };
+                let mut progress = false;
+                while socket.can_send() && *sent < header.len() + body.len() {
+                    let chunk = if *sent < header.len() {
+                        &header[*sent..]
+                    } else {
+                        &body[*sent - header.len()..]
+                    };
+                    match socket.send_slice(chunk) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            *sent += n;
+                            progress = true;
+                        }
+                    }
+                }
+                if *sent == header.len() + body.len() {
+                    socket.close();
+                    self.stage = Stage::Done;
+                    progress = true;
+                }
+                progress
+            }
+            Stage::Done => false,
+        }
+    }
+}
+
+/// Copies the log ring into `out`, starting at a line boundary once it
+/// has wrapped.
+fn snapshot_klogs(out: &mut [u8]) -> usize {
+    let n = crate::log::read_tail(out);
+    if n < out.len() {
+        return n;
+    }
+    let start = out[..n].iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+    out.copy_within(start..n, 0);
+    n - start
+}
@@ -0,0 +1,570 @@
+//! Parsed PCI capabilities: MSI, MSI-X, PCI Express and power management
+//! from the standard list, AER and SR-IOV from extended config space.
+//!
+//! The structures record where each capability lives and what it
+//! advertises; state that changes at run time (link status, power state,
+//! error status) is read from the device by their methods.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::pci::core::PciDevice;
+
+// Standard capability IDs
+const PCI_CAP_ID_PM:    u8 = 0x01;
+const PCI_CAP_ID_MSI:   u8 = 0x05;
+const PCI_CAP_ID_EXP:   u8 = 0x10;
+const PCI_CAP_ID_MSIX:  u8 = 0x11;
+
+// Extended capability IDs
+const PCI_EXT_CAP_ID_AER:   u16 = 0x0001;
+const PCI_EXT_CAP_ID_SRIOV: u16 = 0x0010;
+
+/// Everything `parse` recognised on a function.
+#[derive(Copy, Clone, Default)]
+pub struct PciCaps {
+    pub pm: Option<PowerMgmt>,
+    pub msi: Option<Msi>,
+    pub msix: Option<MsiX>,
+    pub pcie: Option<Pcie>,
+    pub aer: Option<Aer>,
+    pub sriov: Option<SrIov>,
+}
+
+/// Walks the standard list and, for PCI Express functions, extended
+/// config space.
+pub fn parse(dev: &PciDevice) -> PciCaps {
+    let mut caps = PciCaps::default();
+
+    for (offset, id) in dev.capabilities() {
+        match id {
+            PCI_CAP_ID_PM => caps.pm = Some(PowerMgmt::parse(dev, offset)),
+            PCI_CAP_ID_MSI => caps.msi = Some(Msi::parse(dev, offset)),
+            PCI_CAP_ID_EXP => caps.pcie = Some(Pcie::parse(dev, offset)),
+            PCI_CAP_ID_MSIX => caps.msix = Some(MsiX::parse(dev, offset)),
+            _ => {}
+        }
+    }
+
+    // `ext_capabilities` looks at `caps.pcie` to tell whether there is
+    // extended config space at all.
+    let mut dev = *dev;
+    dev.caps = caps;
+
+    for (offset, id, _version) in dev.ext_capabilities() {
+        match id {
+            PCI_EXT_CAP_ID_AER => caps.aer = Some(Aer { offset }),
+            PCI_EXT_CAP_ID_SRIOV => caps.sriov = Some(SrIov::parse(&dev, offset)),
+            _ => {}
+        }
+    }
+
+    caps
+}
+
+// ---------------- POWER MANAGEMENT ----------------
+
+const PCI_PM_CTRL: u16 = 0x04;
+
+const PCI_PM_CTRL_STATE_MASK: u16 = 0x3;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl PowerState {
+    fn from_bits(bits: u16) -> Self {
+        match bits & PCI_PM_CTRL_STATE_MASK {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PowerState::D0 => "D0",
+            PowerState::D1 => "D1",
+            PowerState::D2 => "D2",
+            PowerState::D3Hot => "D3hot",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PowerMgmt {
+    pub offset: u16,
+    pub version: u8,
+    /// States PME can be signalled from: bit 0 D0 .. bit 3 D3hot, bit 4 D3cold.
+    pub pme_support: u8,
+}
+
+impl PowerMgmt {
+    fn parse(dev: &PciDevice, offset: u16) -> Self {
+        let pmc = dev.config_read16(offset + 2);
+        Self {
+            offset,
+            version: (pmc & 0x7) as u8,
+            pme_support: (pmc >> 11) as u8,
+        }
+    }
+
+    pub fn state(&self, dev: &PciDevice) -> PowerState {
+        PowerState::from_bits(dev.config_read16(self.offset + PCI_PM_CTRL))
+    }
+}
+
+// ---------------- MSI ----------------
+
+const PCI_MSI_FLAGS_ENABLE:   u16 = 1 << 0;
+const PCI_MSI_FLAGS_64BIT:    u16 = 1 << 7;
+const PCI_MSI_FLAGS_MASKBIT:  u16 = 1 << 8;
+
+#[derive(Copy, Clone)]
+pub struct Msi {
+    pub offset: u16,
+    pub is_64: bool,
+    pub per_vector_mask: bool,
+    /// Vectors the function can request (Multiple Message Capable).
+    pub vectors: u8,
+}
+
+impl Msi {
+    fn parse(dev: &PciDevice, offset: u16) -> Self {
+        let flags = dev.config_read16(offset + 2);
+        Self {
+            offset,
+            is_64: flags & PCI_MSI_FLAGS_64BIT != 0,
+            per_vector_mask: flags & PCI_MSI_FLAGS_MASKBIT != 0,
+            // Encodings above 32 vectors are reserved.
+            vectors: 1 << ((flags >> 1) & 0x7).min(5),
+        }
+    }
+
+    pub fn enabled(&self, dev: &PciDevice) -> bool {
+        dev.config_read16(self.offset + 2) & PCI_MSI_FLAGS_ENABLE != 0
+    }
+
+    /// Vectors enabled by software (Multiple Message Enable).
+    pub fn enabled_vectors(&self, dev: &PciDevice) -> u8 {
+        1 << ((dev.config_read16(self.offset + 2) >> 4) & 0x7).min(5)
+    }
+
+    /// The Mask Bits register, if the function has per-vector masking.
+    fn mask_reg(&self) -> Option<u16> {
+        self.per_vector_mask
+            .then_some(self.offset + if self.is_64 { 0x10 } else { 0x0C })
+    }
+
+    /// Per-vector mask bits, if the function has them.
+    pub fn mask(&self, dev: &PciDevice) -> Option<u32> {
+        Some(dev.config_read(self.mask_reg()?))
+    }
+
+    /// # Safety
+    /// A masked vector's interrupts are held pending until unmasked.
+    pub unsafe fn set_masked(&self, dev: &PciDevice, vector: u8, masked: bool) -> bool {
+        if vector >= self.vectors {
+            return false;
+        }
+        let Some(reg) = self.mask_reg() else {
+            return false;
+        };
+        let bits = dev.config_read(reg);
+        let bit = 1u32 << vector;
+        dev.config_write(reg, if masked { bits | bit } else { bits & !bit });
+        true
+    }
+}
+
+// ---------------- MSI-X ----------------
+
+const PCI_MSIX_FLAGS_QSIZE:    u16 = 0x7FF;
+const PCI_MSIX_FLAGS_MASKALL:  u16 = 1 << 14;
+const PCI_MSIX_FLAGS_ENABLE:   u16 = 1 << 15;
+
+const PCI_MSIX_ENTRY_SIZE:        u64 = 16;
+const PCI_MSIX_ENTRY_VECTOR_CTRL: u64 = 12;
+const PCI_MSIX_ENTRY_CTRL_MASKBIT: u32 = 1;
+
+#[derive(Copy, Clone)]
+pub struct MsiX {
+    pub offset: u16,
+    pub table_size: u16,
+    /// BAR number (BIR) and offset of the vector table.
+    pub table_bar: u8,
+    pub table_offset: u32,
+    /// BAR number and offset of the Pending Bit Array.
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+impl MsiX {
+    fn parse(dev: &PciDevice, offset: u16) -> Self {
+        let flags = dev.config_read16(offset + 2);
+        let table = dev.config_read(offset + 4);
+        let pba = dev.config_read(offset + 8);
+        Self {
+            offset,
+            table_size: (flags & PCI_MSIX_FLAGS_QSIZE) + 1,
+            table_bar: (table & 0x7) as u8,
+            table_offset: table & !0x7,
+            pba_bar: (pba & 0x7) as u8,
+            pba_offset: pba & !0x7,
+        }
+    }
+
+    pub fn enabled(&self, dev: &PciDevice) -> bool {
+        dev.config_read16(self.offset + 2) & PCI_MSIX_FLAGS_ENABLE != 0
+    }
+
+    pub fn function_masked(&self, dev: &PciDevice) -> bool {
+        dev.config_read16(self.offset + 2) & PCI_MSIX_FLAGS_MASKALL != 0
+    }
+
+    /// Masks or unmasks every vector at once, leaving the per-vector
+    /// mask bits alone.
+    ///
+    /// # Safety
+    /// Interrupts raised while masked are held pending.
+    pub unsafe fn set_function_mask(&self, dev: &PciDevice, masked: bool) {
+        let reg = self.offset + 2;
+        let flags = dev.config_read16(reg);
+        let flags = if masked { flags | PCI_MSIX_FLAGS_MASKALL } else { flags & !PCI_MSIX_FLAGS_MASKALL };
+        // The other half is the read-only capability header.
+        dev.config_write16(reg, flags, 0xFFFF);
+    }
+
+    /// CPU address of the vector table, if its BAR was assigned.
+    pub fn table_addr(&self, dev: &PciDevice) -> Option<u64> {
+        let bar = dev.bars.get(self.table_bar as usize).copied().flatten()?;
+        Some(bar.cpu_addr + self.table_offset as u64)
+    }
+
+    pub fn pba_addr(&self, dev: &PciDevice) -> Option<u64> {
+        let bar = dev.bars.get(self.pba_bar as usize).copied().flatten()?;
+        Some(bar.cpu_addr + self.pba_offset as u64)
+    }
+
+    fn vector_ctrl(&self, dev: &PciDevice, vector: u16) -> Option<*mut u32> {
+        if vector >= self.table_size {
+            return None;
+        }
+        let entry = self.table_addr(dev)? + vector as u64 * PCI_MSIX_ENTRY_SIZE;
+        Some((entry + PCI_MSIX_ENTRY_VECTOR_CTRL) as *mut u32)
+    }
+
+    pub fn masked(&self, dev: &PciDevice, vector: u16) -> Option<bool> {
+        let ctrl = self.vector_ctrl(dev, vector)?;
+        // SAFETY: inside the table, which the BAR maps.
+        Some(unsafe { read_volatile(ctrl) } & PCI_MSIX_ENTRY_CTRL_MASKBIT != 0)
+    }
+
+    /// # Safety
+    /// A masked vector's interrupts are held pending until unmasked.
+    pub unsafe fn set_masked(&self, dev: &PciDevice, vector: u16, masked: bool) -> bool {
+        let Some(ctrl) = self.vector_ctrl(dev, vector) else {
+            return false;
+        };
+        let bits = read_volatile(ctrl);
+        let bits = if masked {
+            bits | PCI_MSIX_ENTRY_CTRL_MASKBIT
+        } else {
+            bits & !PCI_MSIX_ENTRY_CTRL_MASKBIT
+        };
+        write_volatile(ctrl, bits);
+        true
+    }
+
+    /// Whether `vector` has a message pending in the PBA.
+    pub fn pending(&self, dev: &PciDevice, vector: u16) -> Option<bool> {
+        if vector >= self.table_size {
+            return None;
+        }
+        let qword = self.pba_addr(dev)? + (vector as u64 / 64) * 8;
+        // SAFETY: inside the PBA, which the BAR maps.
+        let bits = unsafe { read_volatile(qword as *const u64) };
+        Some(bits & (1 << (vector % 64)) != 0)
+    }
+}
+
+// ---------------- PCI EXPRESS ----------------
+
+const PCI_EXP_FLAGS:   u16 = 0x02;
+const PCI_EXP_DEVCAP:  u16 = 0x04;
+const PCI_EXP_DEVCTL:  u16 = 0x08;
+const PCI_EXP_LNKCAP:  u16 = 0x0C;
+const PCI_EXP_LNKSTA:  u16 = 0x12;
+
+const PCI_EXP_DEVCTL_RELAX_EN: u16 = 1 << 4;
+const PCI_EXP_DEVCTL_NOSNOOP_EN: u16 = 1 << 11;
+
+#[derive(Copy, Clone)]
+pub struct Pcie {
+    pub offset: u16,
+    pub version: u8,
+    pub port_type: u8,
+    /// Max_Payload_Size Supported, in bytes.
+    pub max_payload_supported: u16,
+    /// Supported link speed (PCIe encoding) and width; 0 without a link.
+    pub max_link_speed: u8,
+    pub max_link_width: u8,
+}
+
+/// Negotiated link speed and width.
+#[derive(Copy, Clone)]
+pub struct LinkStatus {
+    pub speed: u8,
+    pub width: u8,
+}
+
+/// Fields of the Device Control register.
+#[derive(Copy, Clone)]
+pub struct DeviceControl {
+    pub max_payload: u16,
+    pub max_read_request: u16,
+    pub relaxed_ordering: bool,
+    pub no_snoop: bool,
+}
+
+impl Pcie {
+    fn parse(dev: &PciDevice, offset: u16) -> Self {
+        let flags = dev.config_read16(offset + PCI_EXP_FLAGS);
+        let devcap = dev.config_read(offset + PCI_EXP_DEVCAP);
+        let lnkcap = dev.config_read(offset + PCI_EXP_LNKCAP);
+        Self {
+            offset,
+            version: (flags & 0xF) as u8,
+            port_type: ((flags >> 4) & 0xF) as u8,
+            max_payload_supported: 128 << (devcap & 0x7),
+            max_link_speed: (lnkcap & 0xF) as u8,
+            max_link_width: ((lnkcap >> 4) & 0x3F) as u8,
+        }
+    }
+
+    pub fn port_type_name(&self) -> &'static str {
+        match self.port_type {
+            0x0 => "Endpoint",
+            0x1 => "Legacy Endpoint",
+            0x4 => "Root Port",
+            0x5 => "Upstream Port",
+            0x6 => "Downstream Port",
+            0x7 => "PCI-Express to PCI Bridge",
+            0x8 => "PCI to PCI-Express Bridge",
+            0x9 => "Root Complex Integrated Endpoint",
+            0xA => "Root Complex Event Collector",
+            _ => "Unknown",
+        }
+    }
+
+    /// Root complex integrated devices have no link.
+    pub fn has_link(&self) -> bool {
+        self.max_link_width != 0
+    }
+
+    pub fn link_status(&self, dev: &PciDevice) -> Option<LinkStatus> {
+        if !self.has_link() {
+            return None;
+        }
+        let lnksta = dev.config_read16(self.offset + PCI_EXP_LNKSTA);
+        Some(LinkStatus {
+            speed: (lnksta & 0xF) as u8,
+            width: ((lnksta >> 4) & 0x3F) as u8,
+        })
+    }
+
+    pub fn device_control(&self, dev: &PciDevice) -> DeviceControl {
+        let devctl = dev.config_read16(self.offset + PCI_EXP_DEVCTL);
+        DeviceControl {
+            max_payload: 128 << ((devctl >> 5) & 0x7),
+            max_read_request: 128 << ((devctl >> 12) & 0x7),
+            relaxed_ordering: devctl & PCI_EXP_DEVCTL_RELAX_EN != 0,
+            no_snoop: devctl & PCI_EXP_DEVCTL_NOSNOOP_EN != 0,
+        }
+    }
+}
+
+/// Link speed encoding as lspci prints it.
+pub fn link_speed_name(speed: u8) -> &'static str {
+    match speed {
+        1 => "2.5GT/s",
+        2 => "5GT/s",
+        3 => "8GT/s",
+        4 => "16GT/s",
+        5 => "32GT/s",
+        6 => "64GT/s",
+        _ => "unknown",
+    }
+}
+
+// ---------------- AER ----------------
+
+const PCI_ERR_UNCOR_STATUS: u16 = 0x04;
+const PCI_ERR_UNCOR_MASK:   u16 = 0x08;
+const PCI_ERR_COR_STATUS:   u16 = 0x10;
+const PCI_ERR_COR_MASK:     u16 = 0x14;
+
+#[derive(Copy, Clone)]
+pub struct Aer {
+    pub offset: u16,
+}
+
+/// Error status and mask registers of the AER capability.
+#[derive(Copy, Clone)]
+pub struct AerStatus {
+    pub uncorrectable: u32,
+    pub uncorrectable_mask: u32,
+    pub correctable: u32,
+    pub correctable_mask: u32,
+}
+
+impl Aer {
+    pub fn status(&self, dev: &PciDevice) -> AerStatus {
+        AerStatus {
+            uncorrectable: dev.config_read(self.offset + PCI_ERR_UNCOR_STATUS),
+            uncorrectable_mask: dev.config_read(self.offset + PCI_ERR_UNCOR_MASK),
+            correctable: dev.config_read(self.offset + PCI_ERR_COR_STATUS),
+            correctable_mask: dev.config_read(self.offset + PCI_ERR_COR_MASK),
+        }
+    }
+}
+
+// ---------------- SR-IOV ----------------
+
+const PCI_SRIOV_CTRL:          u16 = 0x08;
+const PCI_SRIOV_INITIAL_VF:    u16 = 0x0C;
+const PCI_SRIOV_TOTAL_VF:      u16 = 0x0E;
+const PCI_SRIOV_NUM_VF:        u16 = 0x10;
+const PCI_SRIOV_VF_OFFSET:     u16 = 0x14;
+const PCI_SRIOV_VF_STRIDE:     u16 = 0x16;
+const PCI_SRIOV_VF_DID:        u16 = 0x1A;
+
+const PCI_SRIOV_CTRL_VFE: u16 = 1 << 0;
+
+#[derive(Copy, Clone)]
+pub struct SrIov {
+    pub offset: u16,
+    pub initial_vfs: u16,
+    pub total_vfs: u16,
+    /// Routing ID distance from the PF to the first VF, and between VFs.
+    pub vf_offset: u16,
+    pub vf_stride: u16,
+    pub vf_device: u16,
+}
+
+impl SrIov {
+    fn parse(dev: &PciDevice, offset: u16) -> Self {
+        Self {
+            offset,
+            initial_vfs: dev.config_read16(offset + PCI_SRIOV_INITIAL_VF),
+            total_vfs: dev.config_read16(offset + PCI_SRIOV_TOTAL_VF),
+            vf_offset: dev.config_read16(offset + PCI_SRIOV_VF_OFFSET),
+            vf_stride: dev.config_read16(offset + PCI_SRIOV_VF_STRIDE),
+            vf_device: dev.config_read16(offset + PCI_SRIOV_VF_DID),
+        }
+    }
+
+    pub fn enabled(&self, dev: &PciDevice) -> bool {
+        dev.config_read16(self.offset + PCI_SRIOV_CTRL) & PCI_SRIOV_CTRL_VFE != 0
+    }
+
+    pub fn num_vfs(&self, dev: &PciDevice) -> u16 {
+        dev.config_read16(self.offset + PCI_SRIOV_NUM_VF)
+    }
+}
+
+// ---------------- DISPLAY ----------------
+
+/// Capability lines for `pci -v`, lspci style. Each line is printed on
+/// its own: the console formats one print at a time into a bounded
+/// buffer.
+pub fn print(dev: &PciDevice) {
+    let caps = &dev.caps;
+    let on = |b: bool| if b { "+" } else { "-" };
+
+    if let Some(pm) = caps.pm {
+        print!("\tCapabilities: [{:02x}] Power Management version {}: {}, PME(",
+            pm.offset, pm.version, pm.state(dev).as_str());
+        let names = ["D0", "D1", "D2", "D3hot", "D3cold"];
+        for (i, name) in names.iter().enumerate() {
+            print!("{}{}{}", if i > 0 { "," } else { "" }, name, on(pm.pme_support & (1 << i) != 0));
+        }
+        println!(")");
+    }
+
+    if let Some(msi) = caps.msi {
+        println!(
+            "\tCapabilities: [{:02x}] MSI: Enable{} Count={}/{} Maskable{} 64bit{}",
+            msi.offset, on(msi.enabled(dev)), msi.enabled_vectors(dev), msi.vectors,
+            on(msi.per_vector_mask), on(msi.is_64),
+        );
+        if let Some(mask) = msi.mask(dev) {
+            println!("\t\tMasking: {:08x}", mask);
+        }
+    }
+
+    if let Some(pcie) = caps.pcie {
+        println!(
+            "\tCapabilities: [{:02x}] Express (v{}) {}",
+            pcie.offset, pcie.version, pcie.port_type_name(),
+        );
+        let ctl = pcie.device_control(dev);
+        println!(
+            "\t\tDevCtl: RlxdOrd{} NoSnoop{} MaxPayload {} bytes (max {}), MaxReadReq {} bytes",
+            on(ctl.relaxed_ordering), on(ctl.no_snoop), ctl.max_payload,
+            pcie.max_payload_supported, ctl.max_read_request,
+        );
+        if let Some(link) = pcie.link_status(dev) {
+            println!(
+                "\t\tLnkSta: Speed {}, Width x{} (max {}, x{})",
+                link_speed_name(link.speed), link.width,
+                link_speed_name(pcie.max_link_speed), pcie.max_link_width,
+            );
+        }
+    }
+
+    if let Some(msix) = caps.msix {
+        println!(
+            "\tCapabilities: [{:02x}] MSI-X: Enable{} Count={} Masked{}",
+            msix.offset, on(msix.enabled(dev)), msix.table_size,
+            on(msix.function_masked(dev)),
+        );
+        println!("\t\tVector table: BAR={} offset={:08x}", msix.table_bar, msix.table_offset);
+        println!("\t\tPBA: BAR={} offset={:08x}", msix.pba_bar, msix.pba_offset);
+        if let (Some(table), Some(pba)) = (msix.table_addr(dev), msix.pba_addr(dev)) {
+            let vectors = 0..msix.table_size;
+            let masked = vectors.clone().filter(|&v| msix.masked(dev, v) == Some(true)).count();
+            let pending = vectors.filter(|&v| msix.pending(dev, v) == Some(true)).count();
+            println!(
+                "\t\tTable at {:#x}, PBA at {:#x}: {} masked, {} pending",
+                table, pba, masked, pending,
+            );
+        }
+    }
+
+    if let Some(aer) = caps.aer {
+        let status = aer.status(dev);
+        println!("\tCapabilities: [{:03x}] Advanced Error Reporting", aer.offset);
+        println!(
+            "\t\tUESta: {:08x} UEMsk: {:08x} CESta: {:08x} CEMsk: {:08x}",
+            status.uncorrectable, status.uncorrectable_mask,
+            status.correctable, status.correctable_mask,
+        );
+    }
+
+    if let Some(sriov) = caps.sriov {
+        println!(
+            "\tCapabilities: [{:03x}] Single Root I/O Virtualization (SR-IOV): Enable{}",
+            sriov.offset, on(sriov.enabled(dev)),
+        );
+        println!(
+            "\t\tInitial VFs: {}, Total VFs: {}, Number of VFs: {}, VF offset: {}, stride: {}, Device ID: {:04x}",
+            sriov.initial_vfs, sriov.total_vfs, sriov.num_vfs(dev),
+            sriov.vf_offset, sriov.vf_stride, sriov.vf_device,
+        );
+    }
+}
@@ -1,166 +1,784 @@
-use crate::drivers::uart;
+//! PCI enumeration: walks every bus behind the host bridge, numbers
+//! PCI-to-PCI bridges, assigns BARs and bridge windows from the host
+//! bridge `ranges`, and records each function in a device list that
+//! drivers are matched against.
+
+use crate::arch::aarch64::{irq_restore, irq_save};
+use crate::dtb;
+use crate::pci::caps::{self, PciCaps};
 use crate::pci::host::PciHost;
 
+// Config space registers (all header types)
+const PCI_COMMAND_OFFSET:    u16 = 0x04;
+const PCI_CLASS_OFFSET:      u16 = 0x08;
+const PCI_HEADER_OFFSET:     u16 = 0x0C;
+const PCI_BAR0_OFFSET:       u16 = 0x10;
+const PCI_CAP_PTR_OFFSET:    u16 = 0x34;
+const PCI_INTERRUPT_OFFSET:  u16 = 0x3C;
+
+// Type 1 (bridge) header
+const PCI_BUS_NUMBERS_OFFSET:  u16 = 0x18;
+const PCI_IO_WINDOW_OFFSET:    u16 = 0x1C;
+const PCI_MEM_WINDOW_OFFSET:   u16 = 0x20;
+const PCI_PREF_WINDOW_OFFSET:  u16 = 0x24;
+const PCI_PREF_BASE_UPPER:     u16 = 0x28;
+const PCI_PREF_LIMIT_UPPER:    u16 = 0x2C;
+const PCI_IO_UPPER_OFFSET:     u16 = 0x30;
+
+// Low nibble of the prefetchable base: window decodes 64-bit addresses
+const PCI_PREF_RANGE_TYPE: u32 = 0xF;
+const PCI_PREF_RANGE_64:   u32 = 0x1;
+
+const PCI_COMMAND_IO:     u16 = 1 << 0;
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_COMMAND_MASTER: u16 = 1 << 2;
+
 const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
-const PCI_CAP_ID_VENDOR: u8 = 0x09;
 
-const PCI_BAR4_OFFSET: u16 = 0x20;
-const PCI_BAR5_OFFSET: u16 = 0x24;
-const PCI_COMMAND_OFFSET: u16 = 0x04;
+const PCI_EXT_CAP_START: u16 = 0x100;
+
+const PCI_HEADER_TYPE_MASK:   u8 = 0x7F;
+const PCI_HEADER_TYPE_BRIDGE: u8 = 0x01;
+const PCI_HEADER_MULTI_FUNC:  u8 = 0x80;
+
+const PCI_BAR_IO:           u32 = 1 << 0;
+const PCI_BAR_MEM_64:       u32 = 0x2 << 1;
+const PCI_BAR_MEM_TYPE:     u32 = 0x3 << 1;
+const PCI_BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// Bridge windows are 4 KiB (I/O) and 1 MiB (memory) granular.
+const IO_WINDOW_ALIGN:  u64 = 0x1000;
+const MEM_WINDOW_ALIGN: u64 = 0x10_0000;
+
+/// I/O ports below this are left alone, as on PCs.
+const IO_MIN: u64 = 0x1000;
 
-static mut NEXT_MMIO_BASE: u64 = 0x1000_0000;
+// QEMU virt host bridge with highmem=off, used when the DTB has none
+const DEFAULT_IO_CPU_BASE: u64 = 0x3eff_0000;
+const DEFAULT_IO_SIZE:     u64 = 0x1_0000;
+const DEFAULT_MEM_BASE:    u64 = 0x1000_0000;
+const DEFAULT_MEM_SIZE:    u64 = 0x2eff_0000;
+const DEFAULT_LAST_BUS:    u8 = 15;
+
+const MAX_DEVICES: usize = 32;
+const MAX_DRIVERS: usize = 8;
 
 fn align_up(val: u64, align: u64) -> u64 {
     (val + align - 1) & !(align - 1)
 }
 
-pub struct VirtioPciDeviceInfo {
-    pub common_cfg: usize,
-    pub notify_base: usize,
-    pub notify_off_multiplier: u32,
+// ---------------- DEVICES ----------------
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum BarKind {
+    Io,
+    Mem32,
+    Mem64,
 }
 
-pub unsafe fn enumerate(host: &dyn PciHost)
-    -> Option<VirtioPciDeviceInfo>
-{
-    uart::puts("[PCI] Enumerating bus 0...\n");
+#[derive(Copy, Clone)]
+pub struct Bar {
+    pub kind: BarKind,
+    pub prefetchable: bool,
+    /// Address on the PCI bus, as programmed into the BAR.
+    pub addr: u64,
+    pub size: u64,
+    /// Where the CPU sees it.
+    pub cpu_addr: u64,
+}
 
-    for dev in 0u8..32 {
+/// One PCI function, with the resources the enumerator gave it.
+#[derive(Copy, Clone)]
+pub struct PciDevice {
+    pub host: &'static dyn PciHost,
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// Indexed by BAR number; the upper half of a 64-bit BAR is `None`.
+    pub bars: [Option<Bar>; 6],
+    /// INTx line after swizzling through any bridges.
+    pub irq: Option<u32>,
+    /// Index of the upstream bridge in the device list.
+    pub parent: Option<usize>,
+    /// Secondary and subordinate bus of a bridge.
+    pub buses: Option<(u8, u8)>,
+    /// Name of the driver bound to the function.
+    pub driver: Option<&'static str>,
+    /// Capabilities found in config space.
+    pub caps: PciCaps,
+}
 
-        let id = host.read(0, dev, 0, 0x00);
-        let vendor = (id & 0xFFFF) as u16;
+impl PciDevice {
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == PCI_HEADER_TYPE_BRIDGE
+    }
 
-        if vendor == 0xFFFF || vendor == 0 {
-            continue;
+    pub fn config_read(&self, reg: u16) -> u32 {
+        // SAFETY: the function exists and ECAM reads have no side effects.
+        unsafe { self.host.read(self.bus, self.dev, self.func, reg) }
+    }
+
+    pub fn config_read16(&self, reg: u16) -> u16 {
+        (self.config_read(reg & !0x3) >> ((reg & 0x2) * 8)) as u16
+    }
+
+    /// # Safety
+    /// Writes may move BARs or change what the function decodes.
+    pub unsafe fn config_write(&self, reg: u16, val: u32) {
+        self.host.write(self.bus, self.dev, self.func, reg, val);
+    }
+
+    /// Writes a 16-bit register through its dword. Only the bits of the
+    /// other half set in `keep` are written back as read, so callers
+    /// can leave out write-1-to-clear status bits.
+    ///
+    /// # Safety
+    /// As for `config_write`.
+    pub unsafe fn config_write16(&self, reg: u16, val: u16, keep: u16) {
+        let shift = (reg & 0x2) * 8;
+        let dword = self.config_read(reg & !0x3);
+        let rest = dword & ((keep as u32) << (16 - shift));
+        self.config_write(reg & !0x3, rest | (val as u32) << shift);
+    }
+
+    /// Standard capabilities as (config offset, capability ID).
+    pub fn capabilities(&self) -> Capabilities {
+        let status = (self.config_read(PCI_COMMAND_OFFSET) >> 16) as u16;
+        let next = if status & PCI_STATUS_CAP_LIST != 0 {
+            (self.config_read(PCI_CAP_PTR_OFFSET) & 0xFC) as u8
+        } else {
+            0
+        };
+        Capabilities { device: *self, next, remaining: 48 }
+    }
+
+    /// Extended capabilities; only PCI Express functions have them.
+    pub fn ext_capabilities(&self) -> ExtCapabilities {
+        let next = if self.caps.pcie.is_some() { PCI_EXT_CAP_START } else { 0 };
+        ExtCapabilities { device: *self, next, remaining: 960 }
+    }
+}
+
+pub struct Capabilities {
+    device: PciDevice,
+    next: u8,
+    /// A malformed list may loop; there is room for at most 48.
+    remaining: u8,
+}
+
+impl Iterator for Capabilities {
+    type Item = (u16, u8);
+
+    fn next(&mut self) -> Option<(u16, u8)> {
+        if self.next < 0x40 || self.remaining == 0 {
+            return None;
         }
+        self.remaining -= 1;
+
+        let offset = self.next as u16;
+        let header = self.device.config_read(offset);
+        self.next = ((header >> 8) & 0xFC) as u8;
+        Some((offset, header as u8))
+    }
+}
 
-        let device = ((id >> 16) & 0xFFFF) as u16;
+/// Extended capabilities (PCIe config space from 0x100) as
+/// (config offset, capability ID, version).
+pub struct ExtCapabilities {
+    device: PciDevice,
+    next: u16,
+    /// Enough for the 4 KiB space to be full of minimal capabilities.
+    remaining: u16,
+}
 
-        uart::puts("[PCI] Found device: ");
-        uart::putc_hex64(vendor as u64);
-        uart::puts(":");
-        uart::putc_hex64(device as u64);
-        uart::puts("\n");
+impl Iterator for ExtCapabilities {
+    type Item = (u16, u16, u8);
 
-        if vendor != 0x1AF4 || device != 0x1050 {
-            continue;
+    fn next(&mut self) -> Option<(u16, u16, u8)> {
+        if self.next < PCI_EXT_CAP_START || self.remaining == 0 {
+            return None;
         }
+        self.remaining -= 1;
 
-        uart::puts("[PCI] -> VirtIO GPU detected\n");
+        let offset = self.next;
+        let header = self.device.config_read(offset);
+        if header == 0 || header == 0xFFFF_FFFF {
+            return None;
+        }
+        self.next = ((header >> 20) & 0xFFC) as u16;
+        Some((offset, header as u16, ((header >> 16) & 0xF) as u8))
+    }
+}
 
-        // ---- BAR sizing ----
+static mut DEVICES: [Option<PciDevice>; MAX_DEVICES] = [None; MAX_DEVICES];
 
-        let orig_lo = host.read(0, dev, 0, PCI_BAR4_OFFSET);
-        let orig_hi = host.read(0, dev, 0, PCI_BAR5_OFFSET);
+fn add_device(device: PciDevice) -> Option<usize> {
+    unsafe {
+        let devices = &mut *core::ptr::addr_of_mut!(DEVICES);
+        let index = devices.iter().position(|slot| slot.is_none())?;
+        devices[index] = Some(device);
+        Some(index)
+    }
+}
 
-        host.write(0, dev, 0, PCI_BAR4_OFFSET, 0xFFFF_FFFF);
-        host.write(0, dev, 0, PCI_BAR5_OFFSET, 0xFFFF_FFFF);
+fn device_mut(index: usize) -> &'static mut PciDevice {
+    unsafe {
+        let devices = &mut *core::ptr::addr_of_mut!(DEVICES);
+        devices[index].as_mut().unwrap()
+    }
+}
 
-        let size_lo = host.read(0, dev, 0, PCI_BAR4_OFFSET);
-        let size_hi = host.read(0, dev, 0, PCI_BAR5_OFFSET);
+/// Every function found by `enumerate`, in bus order.
+pub fn devices() -> impl Iterator<Item = &'static PciDevice> {
+    let devices: &'static [Option<PciDevice>; MAX_DEVICES] =
+        unsafe { &*core::ptr::addr_of!(DEVICES) };
+    devices.iter().flatten()
+}
 
-        host.write(0, dev, 0, PCI_BAR4_OFFSET, orig_lo);
-        host.write(0, dev, 0, PCI_BAR5_OFFSET, orig_hi);
+/// The function at `bus:dev.func`, if `enumerate` found one.
+pub fn find(bus: u8, dev: u8, func: u8) -> Option<&'static PciDevice> {
+    devices().find(|d| d.bus == bus && d.dev == dev && d.func == func)
+}
 
-        let mask =
-            ((size_hi as u64) << 32) |
-            ((size_lo as u64) & 0xFFFF_FFF0);
+// ---------------- RESOURCES ----------------
 
-        let bar_size = (!mask).wrapping_add(1);
+/// A bus address range handed out bottom-up.
+struct Window {
+    bus_base: u64,
+    cpu_base: u64,
+    next: u64,
+    end: u64,
+}
 
-        let assigned =
-            align_up(NEXT_MMIO_BASE, bar_size);
+impl Window {
+    const fn empty() -> Self {
+        Self { bus_base: 0, cpu_base: 0, next: 0, end: 0 }
+    }
 
-        NEXT_MMIO_BASE = assigned + bar_size;
+    fn new(bus_base: u64, cpu_base: u64, size: u64) -> Self {
+        Self { bus_base, cpu_base, next: bus_base, end: bus_base + size }
+    }
 
-        host.write(
-            0, dev, 0,
-            PCI_BAR4_OFFSET,
-            (assigned as u32) | 0x4,
-        );
+    /// Naturally aligned block of `size` bytes.
+    fn alloc(&mut self, size: u64) -> Option<u64> {
+        if size > self.end - self.bus_base {
+            return None;
+        }
+        let addr = align_up(self.next, size);
+        if addr + size > self.end {
+            return None;
+        }
+        self.next = addr + size;
+        Some(addr)
+    }
 
-        host.write(
-            0, dev, 0,
-            PCI_BAR5_OFFSET,
-            (assigned >> 32) as u32,
-        );
+    fn align(&mut self, align: u64) -> u64 {
+        self.next = align_up(self.next, align).min(self.end);
+        self.next
+    }
 
-        let cmd = host.read(0, dev, 0, PCI_COMMAND_OFFSET);
-        host.write(0, dev, 0, PCI_COMMAND_OFFSET, cmd | 0x2);
+    fn cpu_addr(&self, addr: u64) -> u64 {
+        self.cpu_base + (addr - self.bus_base)
+    }
+}
+
+fn be_u64(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?))
+}
 
-        let bar_lo = host.read(0, dev, 0, PCI_BAR4_OFFSET);
-        let bar_hi = host.read(0, dev, 0, PCI_BAR5_OFFSET);
+/// I/O window, 32-bit memory window, 64-bit memory window (empty if the
+/// host has none) and last bus number from the `pci-host-ecam-generic`
+/// node.
+fn host_windows() -> Option<(Window, Window, Window, u8)> {
+    let root = dtb::get()?.root()?;
+    let node = root
+        .children()
+        .find(|n| n.property_str("compatible") == Some("pci-host-ecam-generic"))?;
+
+    let mut io = Window::empty();
+    let mut mem = Window::empty();
+    let mut mem64 = Window::empty();
+
+    // ranges = <flags pci_hi pci_lo cpu_hi cpu_lo size_hi size_lo>
+    for entry in node.property("ranges")?.chunks_exact(28) {
+        let flags = u32::from_be_bytes(entry[..4].try_into().ok()?);
+        let pci = be_u64(&entry[4..])?;
+        let cpu = be_u64(&entry[12..])?;
+        let size = be_u64(&entry[20..])?;
+
+        match (flags >> 24) & 0x3 {
+            0x1 => io = Window::new(pci, cpu, size),
+            0x2 => mem = Window::new(pci, cpu, size),
+            0x3 => mem64 = Window::new(pci, cpu, size),
+            _ => {}
+        }
+    }
 
-        let bar_base =
-            ((bar_hi as u64) << 32) |
-            ((bar_lo as u64) & 0xFFFF_FFF0);
+    let last_bus = node
+        .property("bus-range")
+        .and_then(|r| r.get(4..8))
+        .map_or(DEFAULT_LAST_BUS, |r| r[3]);
 
-        // ---- capability walk ----
+    Some((io, mem, mem64, last_bus))
+}
 
-        let status =
-            ((host.read(0, dev, 0, 0x04) >> 16) & 0xFFFF) as u16;
+// ---------------- ENUMERATION ----------------
 
-        if (status & PCI_STATUS_CAP_LIST) == 0 {
-            continue;
+struct Scanner {
+    host: &'static dyn PciHost,
+    io: Window,
+    mem: Window,
+    mem64: Window,
+    next_bus: u8,
+    last_bus: u8,
+}
+
+/// Scans everything behind `host`, assigns bus numbers, BARs and bridge
+/// windows, and fills the device list. Returns the number of functions.
+///
+/// # Safety
+/// Reprograms every function; nothing may be using them yet.
+pub unsafe fn enumerate(host: &'static dyn PciHost) -> usize {
+    let (mut io, mem, mem64, last_bus) = host_windows().unwrap_or_else(|| {
+        warn!("No PCI host bridge in the DTB, using QEMU virt defaults");
+        (
+            Window::new(0, DEFAULT_IO_CPU_BASE, DEFAULT_IO_SIZE),
+            Window::new(DEFAULT_MEM_BASE, DEFAULT_MEM_BASE, DEFAULT_MEM_SIZE),
+            Window::empty(),
+            DEFAULT_LAST_BUS,
+        )
+    });
+    io.next = io.next.max(IO_MIN);
+
+    debug!(
+        "PCI windows: io {:#x}..{:#x}, mem {:#x}..{:#x}, buses 0..={}",
+        io.next, io.end, mem.next, mem.end, last_bus,
+    );
+    if mem64.end > mem64.next {
+        debug!("PCI 64-bit window: {:#x}..{:#x}", mem64.next, mem64.end);
+    }
+
+    let mut scanner = Scanner { host, io, mem, mem64, next_bus: 1, last_bus };
+    scanner.scan_bus(0, None);
+
+    devices().count()
+}
+
+impl Scanner {
+    unsafe fn scan_bus(&mut self, bus: u8, parent: Option<usize>) {
+        debug!("Enumerating bus {}...", bus);
+
+        for dev in 0u8..32 {
+            for func in 0u8..8 {
+                let id = self.host.read(bus, dev, func, 0x00);
+                let vendor = (id & 0xFFFF) as u16;
+
+                if vendor == 0xFFFF || vendor == 0 {
+                    if func == 0 {
+                        break;
+                    }
+                    continue;
+                }
+
+                let header = (self.host.read(bus, dev, func, PCI_HEADER_OFFSET) >> 16) as u8;
+
+                if let Some(index) = self.add_function(bus, dev, func, id, header, parent) {
+                    if header & PCI_HEADER_TYPE_MASK == PCI_HEADER_TYPE_BRIDGE {
+                        self.scan_bridge(index);
+                    }
+                }
+
+                if func == 0 && header & PCI_HEADER_MULTI_FUNC == 0 {
+                    break;
+                }
+            }
         }
+    }
 
-        let mut cap_ptr =
-            (host.read(0, dev, 0, 0x34) & 0xFF) as u8;
+    unsafe fn add_function(
+        &mut self,
+        bus: u8,
+        dev: u8,
+        func: u8,
+        id: u32,
+        header: u8,
+        parent: Option<usize>,
+    ) -> Option<usize> {
+        let class_rev = self.host.read(bus, dev, func, PCI_CLASS_OFFSET);
+
+        let mut d = PciDevice {
+            host: self.host,
+            bus,
+            dev,
+            func,
+            vendor: id as u16,
+            device: (id >> 16) as u16,
+            class: (class_rev >> 24) as u8,
+            subclass: (class_rev >> 16) as u8,
+            prog_if: (class_rev >> 8) as u8,
+            revision: class_rev as u8,
+            header_type: header & PCI_HEADER_TYPE_MASK,
+            bars: [None; 6],
+            irq: None,
+            parent,
+            buses: None,
+            driver: None,
+            caps: PciCaps::default(),
+        };
+
+        info!(
+            "Found device {:04x}:{:04x} at {:02x}:{:02x}.{}  {}",
+            d.vendor, d.device, bus, dev, func,
+            class_name(d.class, d.subclass),
+        );
 
-        let mut common_cfg = None;
-        let mut notify_base = None;
-        let mut notify_multiplier = 0;
+        // No decoding while the BARs are sized and moved. The Status half
+        // is write-1-to-clear, so none of it is written back.
+        let cmd = d.config_read16(PCI_COMMAND_OFFSET);
+        d.config_write16(PCI_COMMAND_OFFSET, cmd & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY), 0);
 
-        while cap_ptr != 0 {
+        let bar_count = match d.header_type {
+            0x00 => 6,
+            PCI_HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        self.assign_bars(&mut d, bar_count);
 
-            let header =
-                host.read(0, dev, 0, cap_ptr as u16);
+        // After the BARs: the MSI-X table and PBA are located through them.
+        d.caps = caps::parse(&d);
 
-            let cap_id = (header & 0xFF) as u8;
-            let next = ((header >> 8) & 0xFF) as u8;
+        // Interrupt Pin is byte 1 of the Interrupt Line register
+        let pin = (d.config_read(PCI_INTERRUPT_OFFSET) >> 8) as u8;
+        d.irq = self.route_intx(dev, pin, parent);
 
-            if cap_id == PCI_CAP_ID_VENDOR {
+        let mut enable = PCI_COMMAND_MASTER;
+        if d.is_bridge() || d.bars.iter().flatten().any(|b| b.kind == BarKind::Io) {
+            enable |= PCI_COMMAND_IO;
+        }
+        if d.is_bridge() || d.bars.iter().flatten().any(|b| b.kind != BarKind::Io) {
+            enable |= PCI_COMMAND_MEMORY;
+        }
+        d.config_write16(PCI_COMMAND_OFFSET, cmd | enable, 0);
+
+        let index = add_device(d);
+        if index.is_none() {
+            warn!("PCI device list full, {:02x}:{:02x}.{} not recorded", bus, dev, func);
+        }
+        index
+    }
+
+    unsafe fn assign_bars(&mut self, d: &mut PciDevice, count: usize) {
+        let mut i = 0;
+        while i < count {
+            let reg = PCI_BAR0_OFFSET + 4 * i as u16;
 
-                let cap = host.read(0, dev, 0, cap_ptr as u16);
-                let cfg_type =
-                    ((cap >> 24) & 0xFF) as u8;
+            let orig = d.config_read(reg);
+            d.config_write(reg, 0xFFFF_FFFF);
+            let probe = d.config_read(reg);
+            d.config_write(reg, orig);
 
-                let offset =
-                    host.read(0, dev, 0, cap_ptr as u16 + 0x08);
+            if probe == 0 {
+                i += 1;
+                continue;
+            }
 
-                if cfg_type == 1 {
-                    uart::puts("[PCI] Found common config\n");
-                    common_cfg =
-                        Some(bar_base + offset as u64);
+            if probe & PCI_BAR_IO != 0 {
+                // Devices may implement only 16 address bits.
+                let mut mask = probe & !0x3;
+                if mask >> 16 == 0 {
+                    mask |= 0xFFFF_0000;
+                }
+                let size = (!mask).wrapping_add(1) as u64;
+
+                match self.io.alloc(size) {
+                    Some(addr) => {
+                        d.config_write(reg, addr as u32);
+                        d.bars[i] = Some(Bar {
+                            kind: BarKind::Io,
+                            prefetchable: false,
+                            addr,
+                            size,
+                            cpu_addr: self.io.cpu_addr(addr),
+                        });
+                    }
+                    None => warn!("  BAR{}: no room for {:#x} bytes of I/O", i, size),
                 }
 
-                if cfg_type == 2 {
-                    uart::puts("[PCI] Found notify config\n");
+                i += 1;
+                continue;
+            }
+
+            let is_64 = probe & PCI_BAR_MEM_TYPE == PCI_BAR_MEM_64 && i + 1 < count;
+            let mut mask = (probe & !0xF) as u64;
 
-                    notify_base =
-                        Some(bar_base + offset as u64);
+            if is_64 {
+                let orig_hi = d.config_read(reg + 4);
+                d.config_write(reg + 4, 0xFFFF_FFFF);
+                mask |= (d.config_read(reg + 4) as u64) << 32;
+                d.config_write(reg + 4, orig_hi);
+            } else {
+                mask |= 0xFFFF_FFFF_0000_0000;
+            }
+
+            let size = (!mask).wrapping_add(1);
+            let prefetchable = probe & PCI_BAR_PREFETCHABLE != 0;
+
+            // 64-bit BARs go above 4 GiB when the host has a window there.
+            // Behind a bridge that needs its prefetchable window, so only
+            // prefetchable BARs qualify; the rest stay in the 32-bit one.
+            let high = if is_64 && (prefetchable || d.parent.is_none()) {
+                self.mem64.alloc(size).map(|addr| (addr, self.mem64.cpu_addr(addr)))
+            } else {
+                None
+            };
+            let placed = high.or_else(|| {
+                self.mem.alloc(size).map(|addr| (addr, self.mem.cpu_addr(addr)))
+            });
 
-                    notify_multiplier =
-                        host.read(0, dev, 0,
-                            cap_ptr as u16 + 0x10);
+            match placed {
+                Some((addr, cpu_addr)) => {
+                    d.config_write(reg, (addr as u32) | (probe & 0xF));
+                    if is_64 {
+                        d.config_write(reg + 4, (addr >> 32) as u32);
+                    }
+                    d.bars[i] = Some(Bar {
+                        kind: if is_64 { BarKind::Mem64 } else { BarKind::Mem32 },
+                        prefetchable,
+                        addr,
+                        size,
+                        cpu_addr,
+                    });
+                    debug!("  BAR{}: {:#x} bytes at {:#x}", i, size, addr);
                 }
+                None => warn!("  BAR{}: no room for {:#x} bytes of memory", i, size),
             }
 
-            cap_ptr = next;
+            i += if is_64 { 2 } else { 1 };
         }
+    }
 
-        if let (Some(c), Some(n)) =
-            (common_cfg, notify_base)
-        {
-            uart::puts("[PCI] Returning VirtIO PCI transport info\n");
+    /// Numbers the bridge's secondary bus, scans it and opens windows
+    /// covering everything assigned below it.
+    unsafe fn scan_bridge(&mut self, index: usize) {
+        let b = *device_mut(index);
+
+        let secondary = self.next_bus;
+        self.next_bus = match secondary.checked_add(1) {
+            Some(next) if secondary <= self.last_bus => next,
+            _ => {
+                warn!("Out of bus numbers, bridge at {:02x}:{:02x}.{} skipped", b.bus, b.dev, b.func);
+                return;
+            }
+        };
+
+        // Subordinate is open until the buses below are numbered.
+        let latency = b.config_read(PCI_BUS_NUMBERS_OFFSET) & 0xFF00_0000;
+        let set_buses = |subordinate: u8| {
+            b.config_write(
+                PCI_BUS_NUMBERS_OFFSET,
+                latency
+                    | (subordinate as u32) << 16
+                    | (secondary as u32) << 8
+                    | b.bus as u32,
+            );
+        };
+        set_buses(self.last_bus);
+
+        // Without a 64-bit prefetchable window the 64-bit host window is
+        // out of reach for everything below.
+        let pref64 = b.config_read(PCI_PREF_WINDOW_OFFSET) & PCI_PREF_RANGE_TYPE == PCI_PREF_RANGE_64;
+        let hidden_mem64 = (!pref64).then(|| core::mem::replace(&mut self.mem64, Window::empty()));
+
+        let io_start = self.io.align(IO_WINDOW_ALIGN);
+        let mem_start = self.mem.align(MEM_WINDOW_ALIGN);
+        let pref_start = self.mem64.align(MEM_WINDOW_ALIGN);
+
+        self.scan_bus(secondary, Some(index));
+
+        let io_end = self.io.align(IO_WINDOW_ALIGN);
+        let mem_end = self.mem.align(MEM_WINDOW_ALIGN);
+        let pref_end = self.mem64.align(MEM_WINDOW_ALIGN);
+
+        if let Some(mem64) = hidden_mem64 {
+            self.mem64 = mem64;
+        }
 
-            return Some(VirtioPciDeviceInfo {
-                common_cfg: c as usize,
-                notify_base: n as usize,
-                notify_off_multiplier: notify_multiplier,
+        let subordinate = self.next_bus - 1;
+        set_buses(subordinate);
+        device_mut(index).buses = Some((secondary, subordinate));
+
+        // An empty window is closed by putting its base above its limit.
+        let (io_base, io_limit) = if io_end > io_start {
+            (io_start, io_end - 1)
+        } else {
+            (0xF000, 0)
+        };
+        b.config_write(
+            PCI_IO_WINDOW_OFFSET,
+            ((io_limit >> 8) as u32 & 0xF0) << 8 | ((io_base >> 8) as u32 & 0xF0),
+        );
+        b.config_write(
+            PCI_IO_UPPER_OFFSET,
+            ((io_limit >> 16) as u32) << 16 | (io_base >> 16) as u32 & 0xFFFF,
+        );
+
+        let (mem_base, mem_limit) = if mem_end > mem_start {
+            (mem_start, mem_end - 1)
+        } else {
+            (0xFFF0_0000, 0)
+        };
+        b.config_write(
+            PCI_MEM_WINDOW_OFFSET,
+            ((mem_limit >> 16) as u32 & 0xFFF0) << 16 | ((mem_base >> 16) as u32 & 0xFFF0),
+        );
+
+        // The prefetchable window covers what went into the 64-bit host
+        // window; prefetchable BARs below 4 GiB share the memory window.
+        let (pref_base, pref_limit) = if pref_end > pref_start {
+            (pref_start, pref_end - 1)
+        } else {
+            (0xFFF0_0000, 0)
+        };
+        b.config_write(
+            PCI_PREF_WINDOW_OFFSET,
+            ((pref_limit >> 16) as u32 & 0xFFF0) << 16 | ((pref_base >> 16) as u32 & 0xFFF0),
+        );
+        if pref64 {
+            b.config_write(PCI_PREF_BASE_UPPER, (pref_base >> 32) as u32);
+            b.config_write(PCI_PREF_LIMIT_UPPER, (pref_limit >> 32) as u32);
+        }
+
+        debug!(
+            "Bridge {:02x}:{:02x}.{}: buses {}..={}, io {:#x}..{:#x}, mem {:#x}..{:#x}, pref {:#x}..{:#x}",
+            b.bus, b.dev, b.func, secondary, subordinate,
+            io_start, io_end, mem_start, mem_end, pref_start, pref_end,
+        );
+    }
+
+    /// Follows INTx `pin` of slot `dev` up through the bridges: each one
+    /// rotates the pin by the slot number of the device below it.
+    fn route_intx(&self, mut dev: u8, mut pin: u8, mut parent: Option<usize>) -> Option<u32> {
+        if !(1..=4).contains(&pin) {
+            return None;
+        }
+        while let Some(index) = parent {
+            pin = (pin - 1 + dev) % 4 + 1;
+            let bridge = device_mut(index);
+            dev = bridge.dev;
+            parent = bridge.parent;
+        }
+        self.host.intx_irq(dev, pin)
+    }
+}
+
+// ---------------- DRIVERS ----------------
+
+/// What a driver binds to; `None` fields match anything.
+#[derive(Copy, Clone)]
+pub struct PciMatch {
+    pub vendor: Option<u16>,
+    pub device: Option<u16>,
+    pub class: Option<(u8, u8)>,
+}
+
+impl PciMatch {
+    pub const fn id(vendor: u16, device: u16) -> Self {
+        Self { vendor: Some(vendor), device: Some(device), class: None }
+    }
+
+    fn matches(&self, d: &PciDevice) -> bool {
+        self.vendor.is_none_or(|v| v == d.vendor)
+            && self.device.is_none_or(|v| v == d.device)
+            && self.class.is_none_or(|c| c == (d.class, d.subclass))
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub ids: &'static [PciMatch],
+    /// Brings the device up; false leaves it for other drivers.
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+static mut DRIVERS: [Option<&'static PciDriver>; MAX_DRIVERS] = [None; MAX_DRIVERS];
+
+pub fn register_driver(driver: &'static PciDriver) {
+    let daif = irq_save();
+    let added = unsafe {
+        let drivers = &mut *core::ptr::addr_of_mut!(DRIVERS);
+        match drivers.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(driver);
+                true
+            }
+            None => false,
+        }
+    };
+    irq_restore(daif);
+
+    if !added {
+        warn!("PCI driver table full, {} not registered", driver.name);
+    }
+}
+
+/// Offers every unbound device to the registered drivers, in
+/// registration order, and binds it to the first whose probe succeeds.
+pub fn probe_drivers() {
+    let drivers: &[Option<&'static PciDriver>; MAX_DRIVERS] =
+        unsafe { &*core::ptr::addr_of!(DRIVERS) };
+
+    for index in 0..MAX_DEVICES {
+        let Some(d) = (unsafe { (*core::ptr::addr_of!(DEVICES))[index] }) else {
+            continue;
+        };
+        if d.driver.is_some() {
+            continue;
+        }
+
+        let bound = drivers
+            .iter()
+            .flatten()
+            .filter(|drv| drv.ids.iter().any(|id| id.matches(&d)))
+            .find(|drv| {
+                debug!("Probing {} for {:02x}:{:02x}.{}", drv.name, d.bus, d.dev, d.func);
+                (drv.probe)(&d)
             });
+
+        if let Some(drv) = bound {
+            device_mut(index).driver = Some(drv.name);
         }
     }
+}
 
-    None
-}
\ No newline at end of file
+/// Human-readable name for a PCI class/subclass pair (lspci style).
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _)    => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _)    => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _)    => "Display controller",
+        (0x04, _)    => "Multimedia controller",
+        (0x05, _)    => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _)    => "Bridge",
+        (0x07, _)    => "Communication controller",
+        (0x08, _)    => "System peripheral",
+        (0x09, _)    => "Input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, _)    => "Serial bus controller",
+        (0xFF, _)    => "Unassigned class",
+        _            => "Unknown class",
+    }
+}
@@ -3,4 +3,8 @@ pub mod qemu_virt;
 pub trait PciHost {
     unsafe fn read(&self, bus: u8, dev: u8, func: u8, reg: u16) -> u32;
     unsafe fn write(&self, bus: u8, dev: u8, func: u8, reg: u16, val: u32);
+
+    /// GIC INTID that INTx `pin` (1 = INTA) of a device in slot `dev`
+    /// on bus 0 is routed to.
+    fn intx_irq(&self, dev: u8, pin: u8) -> Option<u32>;
 }
\ No newline at end of file
@@ -3,6 +3,9 @@ use super::PciHost;
 
 const ECAM_BASE: usize = 0x3f000000; // adjust if needed
 
+/// INTA..INTD of the host bridge: SPIs 3..=6 in the DTB `interrupt-map`.
+const INTX_BASE: u32 = 35;
+
 pub struct QemuVirtPci;
 
 impl PciHost for QemuVirtPci {
@@ -27,4 +30,12 @@ impl PciHost for QemuVirtPci {
         let addr = (ECAM_BASE + offset) as *mut u32;
         write_volatile(addr, val);
     }
-}
\ No newline at end of file
+
+    /// QEMU virt swizzles INTx by slot, as the DTB `interrupt-map` says.
+    fn intx_irq(&self, dev: u8, pin: u8) -> Option<u32> {
+        if !(1..=4).contains(&pin) {
+            return None;
+        }
+        Some(INTX_BASE + (dev as u32 + pin as u32 - 1) % 4)
+    }
+}
@@ -1,2 +1,128 @@
 pub mod host;
-pub mod core;
\ No newline at end of file
+pub mod core;
+pub mod caps;
+
+use crate::shell::Command;
+use self::core::BarKind;
+
+pub static SHELL_COMMANDS: &[Command] = &[
+    Command { name: "pci", help: "pci [-v|mask ...]    list PCI functions, mask MSI/MSI-X vectors", run: lspci },
+];
+
+fn lspci(args: &[&str]) {
+    let verbose = match args {
+        [_] => false,
+        [_, "-v"] => true,
+        [_, "mask", bdf, vector, state] => {
+            mask(bdf, vector, state);
+            return;
+        }
+        _ => {
+            println!("usage: pci [-v]");
+            println!("       pci mask <bb:dd.f> <vector|all> <on|off>");
+            return;
+        }
+    };
+
+    for d in self::core::devices() {
+        print!(
+            "{:02x}:{:02x}.{} [{:02x}{:02x}] {:04x}:{:04x} rev {:02x}  {}",
+            d.bus, d.dev, d.func, d.class, d.subclass, d.vendor, d.device,
+            d.revision,
+            self::core::class_name(d.class, d.subclass),
+        );
+        if d.prog_if != 0 {
+            print!(" (prog-if {:02x})", d.prog_if);
+        }
+        if let Some((secondary, subordinate)) = d.buses {
+            print!("  [bus {:02x}-{:02x}]", secondary, subordinate);
+        }
+        match d.driver {
+            Some(driver) => println!("  ({})", driver),
+            None => println!(),
+        }
+
+        if verbose {
+            print_details(d);
+        }
+    }
+}
+
+/// Masks or unmasks one MSI-X vector, or all of them through the
+/// Function Mask bit; functions with only MSI use its per-vector mask.
+fn mask(bdf: &str, vector: &str, state: &str) {
+    let Some((bus, dev, func)) = parse_bdf(bdf) else {
+        println!("bad address '{}', expected bb:dd.f", bdf);
+        return;
+    };
+    let Some(d) = self::core::find(bus, dev, func) else {
+        println!("no function at {}", bdf);
+        return;
+    };
+    let masked = match state {
+        "on" => true,
+        "off" => false,
+        _ => {
+            println!("expected on or off, not '{}'", state);
+            return;
+        }
+    };
+
+    let done = match (d.caps.msix, d.caps.msi, vector) {
+        (Some(msix), _, "all") => {
+            // SAFETY: masking only holds interrupts back.
+            unsafe { msix.set_function_mask(d, masked) };
+            true
+        }
+        (Some(msix), _, v) => v
+            .parse()
+            .is_ok_and(|v| unsafe { msix.set_masked(d, v, masked) }),
+        (None, Some(msi), v) => v
+            .parse()
+            .is_ok_and(|v| unsafe { msi.set_masked(d, v, masked) }),
+        (None, None, _) => {
+            println!("{} has neither MSI nor MSI-X", bdf);
+            return;
+        }
+    };
+    if !done {
+        println!("cannot mask vector '{}' of {}", vector, bdf);
+    }
+}
+
+fn parse_bdf(s: &str) -> Option<(u8, u8, u8)> {
+    let (bus, rest) = s.split_once(':')?;
+    let (dev, func) = rest.split_once('.')?;
+    Some((
+        u8::from_str_radix(bus, 16).ok()?,
+        u8::from_str_radix(dev, 16).ok()?,
+        func.parse().ok()?,
+    ))
+}
+
+fn print_details(d: &self::core::PciDevice) {
+    if let Some(irq) = d.irq {
+        println!("\tInterrupt: INTID {}", irq);
+    }
+
+    for (i, bar) in d.bars.iter().enumerate() {
+        let Some(bar) = bar else {
+            continue;
+        };
+        match bar.kind {
+            BarKind::Io => println!(
+                "\tRegion {}: I/O ports at {:04x} [size={:#x}]",
+                i, bar.addr, bar.size,
+            ),
+            kind => println!(
+                "\tRegion {}: Memory at {:08x} ({}-bit, {}) [size={:#x}]",
+                i, bar.addr,
+                if kind == BarKind::Mem64 { 64 } else { 32 },
+                if bar.prefetchable { "prefetchable" } else { "non-prefetchable" },
+                bar.size,
+            ),
+        }
+    }
+
+    caps::print(d);
+}
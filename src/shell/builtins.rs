@@ -0,0 +1,87 @@
+use super::Command;
+use crate::arch::aarch64::{get_current_time_ms, psci, rng};
+use crate::drivers::{allocator, gic, uart};
+
+pub static COMMANDS: &[Command] = &[
+    Command { name: "help",     help: "help                 list commands",              run: help },
+    Command { name: "uptime",   help: "uptime               time since boot",            run: uptime },
+    Command { name: "mem",      help: "mem                  kernel arena usage",         run: mem },
+    Command { name: "irq",      help: "irq                  interrupt counts per INTID", run: irq },
+    Command { name: "rng",      help: "rng                  random bytes from the DRBG", run: random },
+    Command { name: "reboot",   help: "reboot               reset the node (PSCI)",      run: reboot },
+    Command { name: "poweroff", help: "poweroff             power the node off (PSCI)",  run: poweroff },
+];
+
+fn help(_args: &[&str]) {
+    for cmd in super::commands() {
+        println!("  {}", cmd.help);
+    }
+}
+
+fn uptime(_args: &[&str]) {
+    let ms = get_current_time_ms();
+    let secs = ms / 1000;
+    println!(
+        "up {}d {:02}:{:02}:{:02}.{:03}",
+        secs / 86400,
+        (secs / 3600) % 24,
+        (secs / 60) % 60,
+        secs % 60,
+        ms % 1000,
+    );
+}
+
+fn mem(_args: &[&str]) {
+    let (used, total) = allocator::stats();
+    println!("arena: {} KiB used / {} KiB total ({}%)", used / 1024, total / 1024, used * 100 / total);
+    println!("free:  {} KiB (bump allocator, never reclaimed)", (total - used) / 1024);
+}
+
+/// Well-known INTIDs on QEMU virt.
+fn irq_name(intid: u32) -> &'static str {
+    match intid {
+        27 => "virtual timer",
+        30 => "physical timer",
+        uart::UART_IRQ => "pl011",
+        35..=38 => "pcie intx",
+        48..=79 => "virtio-mmio",
+        _ => "",
+    }
+}
+
+fn irq(_args: &[&str]) {
+    println!("INTID  COUNT       SOURCE");
+    for intid in 0..gic::MAX_INTID as u32 {
+        let count = gic::irq_count(intid);
+        if count != 0 {
+            println!("{:<6} {:<11} {}", intid, count, irq_name(intid));
+        }
+    }
+    let overruns = uart::rx_overruns();
+    if overruns != 0 {
+        println!("pl011 rx overruns: {}", overruns);
+    }
+}
+
+fn random(_args: &[&str]) {
+    println!("seeded from {}", rng::source().as_str());
+
+    let mut bytes = [0u8; 32];
+    rng::fill_bytes(&mut bytes);
+    for b in bytes {
+        print!("{:02x}", b);
+    }
+    println!();
+}
+
+fn reboot(_args: &[&str]) {
+    println!("Rebooting...");
+    uart::flush();
+    psci::system_reset();
+}
+
+fn poweroff(_args: &[&str]) {
+    println!("Powering off...");
+    uart::flush();
+    psci::system_off();
+}
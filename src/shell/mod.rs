@@ -0,0 +1,186 @@
+//! Interactive administration shell on the PL011.
+//!
+//! Subsystems contribute commands as static tables passed to
+//! `register`; the shell itself only owns line editing, history and
+//! dispatch.
+
+pub mod builtins;
+
+use crate::drivers::console::{LineEditor, LineEvent, LINE_MAX};
+use crate::drivers::uart;
+
+const PROMPT: &str = "aether> ";
+
+const MAX_TABLES: usize = 16;
+const MAX_ARGS: usize = 16;
+const HISTORY_LEN: usize = 16;
+
+pub struct Command {
+    pub name: &'static str,
+    /// Usage line shown by `help`.
+    pub help: &'static str,
+    /// Called with the command name as `args[0]`.
+    pub run: fn(args: &[&str]),
+}
+
+static mut TABLES: [&[Command]; MAX_TABLES] = [&[]; MAX_TABLES];
+static mut TABLE_COUNT: usize = 0;
+
+/// Adds a subsystem's commands. Returns false when the table list is full.
+pub fn register(table: &'static [Command]) -> bool {
+    unsafe {
+        if TABLE_COUNT == MAX_TABLES {
+            return false;
+        }
+        TABLES[TABLE_COUNT] = table;
+        TABLE_COUNT += 1;
+    }
+    true
+}
+
+/// Every registered command, in registration order.
+pub fn commands() -> impl Iterator<Item = &'static Command> {
+    let tables: &'static [&'static [Command]] = unsafe {
+        let tables = &*core::ptr::addr_of!(TABLES);
+        &tables[..TABLE_COUNT]
+    };
+    tables.iter().flat_map(|table| table.iter())
+}
+
+fn find(name: &str) -> Option<&'static Command> {
+    commands().find(|cmd| cmd.name == name)
+}
+
+// ---------------- HISTORY ----------------
+
+struct History {
+    lines: [[u8; LINE_MAX]; HISTORY_LEN],
+    lens: [usize; HISTORY_LEN],
+    /// Total lines ever recorded; the newest is at `(count - 1) % HISTORY_LEN`.
+    count: usize,
+    /// How far back the user has scrolled (0 = editing a new line).
+    cursor: usize,
+}
+
+impl History {
+    const fn new() -> Self {
+        Self {
+            lines: [[0; LINE_MAX]; HISTORY_LEN],
+            lens: [0; HISTORY_LEN],
+            count: 0,
+            cursor: 0,
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        self.cursor = 0;
+        if line.is_empty() || self.get(1) == Some(line) {
+            return;
+        }
+        let slot = self.count % HISTORY_LEN;
+        let n = line.len().min(LINE_MAX);
+        self.lines[slot][..n].copy_from_slice(&line.as_bytes()[..n]);
+        self.lens[slot] = n;
+        self.count += 1;
+    }
+
+    /// The entry `back` lines from the end: 1 is the newest, 0 is none.
+    fn get(&self, back: usize) -> Option<&str> {
+        if back == 0 || back > self.count.min(HISTORY_LEN) {
+            return None;
+        }
+        let slot = (self.count - back) % HISTORY_LEN;
+        core::str::from_utf8(&self.lines[slot][..self.lens[slot]]).ok()
+    }
+
+    fn older(&mut self) -> Option<&str> {
+        if self.cursor < self.count.min(HISTORY_LEN) {
+            self.cursor += 1;
+        }
+        self.get(self.cursor)
+    }
+
+    fn newer(&mut self) -> &str {
+        self.cursor = self.cursor.saturating_sub(1);
+        self.get(self.cursor).unwrap_or("")
+    }
+}
+
+// ---------------- SHELL ----------------
+
+struct Shell {
+    editor: LineEditor,
+    history: History,
+}
+
+static mut SHELL: Shell = Shell {
+    editor: LineEditor::new(),
+    history: History::new(),
+};
+
+/// Registers the built-in commands and prints the first prompt.
+pub fn init() {
+    register(builtins::COMMANDS);
+    println!("Type 'help' for a list of commands.");
+    print!("{}", PROMPT);
+}
+
+/// Processes pending console input. Called from the main loop; never blocks.
+pub fn poll() {
+    // SAFETY: only the main loop touches the shell state.
+    let shell = unsafe { &mut *core::ptr::addr_of_mut!(SHELL) };
+
+    let mut input = [0u8; 32];
+    loop {
+        let n = uart::read(&mut input);
+        if n == 0 {
+            break;
+        }
+        for &b in &input[..n] {
+            match shell.editor.feed(b) {
+                LineEvent::Pending => {}
+                LineEvent::Line => {
+                    let line = shell.editor.line();
+                    shell.history.push(line.trim());
+                    execute(line);
+                    print!("{}", PROMPT);
+                }
+                LineEvent::Cancelled => {
+                    shell.history.cursor = 0;
+                    print!("{}", PROMPT);
+                }
+                LineEvent::Up => {
+                    if let Some(entry) = shell.history.older() {
+                        shell.editor.set_line(entry);
+                    }
+                }
+                LineEvent::Down => {
+                    shell.editor.set_line(shell.history.newer());
+                }
+            }
+        }
+    }
+}
+
+/// Splits a line on whitespace and runs the matching command.
+pub fn execute(line: &str) {
+    let mut args = [""; MAX_ARGS];
+    let mut argc = 0;
+    for word in line.split_ascii_whitespace() {
+        if argc == MAX_ARGS {
+            println!("too many arguments (max {})", MAX_ARGS);
+            return;
+        }
+        args[argc] = word;
+        argc += 1;
+    }
+
+    if argc == 0 {
+        return;
+    }
+
+    match find(args[0]) {
+        Some(cmd) => (cmd.run)(&args[..argc]),
+        None => println!("{}: command not found (try 'help')", args[0]),
+    }
+}
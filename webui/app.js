@@ -0,0 +1,19 @@
+// KLogs window: mirror the kernel log ring served at /klogs.
+const logOutput = document.getElementById('log-output');
+
+async function refreshKlogs() {
+    try {
+        const res = await fetch('/klogs', { cache: 'no-store' });
+        if (!res.ok) return;
+        const text = await res.text();
+        const atBottom =
+            logOutput.scrollTop + logOutput.clientHeight >= logOutput.scrollHeight - 4;
+        logOutput.textContent = text;
+        if (atBottom) logOutput.scrollTop = logOutput.scrollHeight;
+    } catch (e) {
+        // Node unreachable; keep showing the last snapshot.
+    }
+}
+
+refreshKlogs();
+setInterval(refreshKlogs, 2000);